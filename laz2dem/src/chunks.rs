@@ -0,0 +1,136 @@
+use crate::read::table_exists;
+use las::Header;
+use rusqlite::Connection;
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+use tilemath::bbox::BBox;
+
+/// Consecutive points of the file which can be read without decompressing preceding points
+pub struct Chunk {
+    pub first_point: u64,
+    pub point_count: u64,
+    /// In the projection of the file
+    pub bbox: BBox,
+}
+
+impl Chunk {
+    pub fn intersects(&self, bbox: &BBox) -> bool {
+        self.bbox.max_x >= bbox.min_x
+            && self.bbox.min_x <= bbox.max_x
+            && self.bbox.max_y >= bbox.min_y
+            && self.bbox.min_y <= bbox.max_y
+    }
+}
+
+/// Reads chunks of the file stored by `lazindex --chunks`; `None` if the file has no chunks indexed.
+pub fn indexed_chunks(conn: &Connection, file: &str) -> Option<Vec<Chunk>> {
+    if !table_exists(conn, "laz_chunk") {
+        return None;
+    }
+
+    let mut stmt = conn
+        .prepare_cached("SELECT first_point, point_count, min_x, max_x, min_y, max_y FROM laz_chunk WHERE file = ?1")
+        .unwrap();
+
+    let chunks: Vec<_> = stmt
+        .query_map([file], |row| {
+            Ok(Chunk {
+                first_point: row.get(0)?,
+                point_count: row.get(1)?,
+                bbox: BBox::new(row.get(2)?, row.get(4)?, row.get(3)?, row.get(5)?),
+            })
+        })
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect();
+
+    (!chunks.is_empty()).then_some(chunks)
+}
+
+/// Reads nodes of the COPC octree as chunks; `None` if the file is not COPC.
+///
+/// See <https://copc.io/>. Nodes of all depths are returned as every node holds points of its whole cube.
+pub fn copc_chunks(path: &Path, header: &Header) -> Option<Vec<Chunk>> {
+    let info = &header
+        .vlrs()
+        .iter()
+        .find(|vlr| vlr.user_id == "copc" && vlr.record_id == 1)?
+        .data;
+
+    let f64_at = |offset: usize| f64::from_le_bytes(info[offset..offset + 8].try_into().unwrap());
+
+    let u64_at = |offset: usize| u64::from_le_bytes(info[offset..offset + 8].try_into().unwrap());
+
+    let (center_x, center_y, halfsize) = (f64_at(0), f64_at(8), f64_at(24));
+
+    let mut file = File::open(path).unwrap();
+
+    // (file offset of compressed data, point count, bbox)
+    let mut nodes = Vec::new();
+
+    let mut pages = vec![(u64_at(40), u64_at(48))];
+
+    while let Some((offset, size)) = pages.pop() {
+        let mut page = vec![0; size as usize];
+
+        file.seek(SeekFrom::Start(offset)).unwrap();
+
+        file.read_exact(&mut page).unwrap();
+
+        for entry in page.chunks_exact(32) {
+            let i32_at =
+                |offset: usize| i32::from_le_bytes(entry[offset..offset + 4].try_into().unwrap());
+
+            let (depth, x, y) = (i32_at(0), i32_at(4), i32_at(8));
+
+            let offset = u64::from_le_bytes(entry[16..24].try_into().unwrap());
+
+            let byte_size = i32_at(24);
+
+            let point_count = i32_at(28);
+
+            match point_count {
+                -1 => pages.push((offset, byte_size as u64)),
+                0 => {}
+                _ => {
+                    let side = 2.0 * halfsize / f64::from(1u32 << depth);
+
+                    let min_x = center_x - halfsize + f64::from(x) * side;
+
+                    let min_y = center_y - halfsize + f64::from(y) * side;
+
+                    nodes.push((
+                        offset,
+                        point_count as u64,
+                        BBox::new(min_x, min_y, min_x + side, min_y + side),
+                    ));
+                }
+            }
+        }
+    }
+
+    // point chunks are stored in the order of their data
+    nodes.sort_by_key(|(offset, _, _)| *offset);
+
+    let mut first_point = 0;
+
+    Some(
+        nodes
+            .into_iter()
+            .map(|(_, point_count, bbox)| {
+                let chunk = Chunk {
+                    first_point,
+                    point_count,
+                    bbox,
+                };
+
+                first_point += point_count;
+
+                chunk
+            })
+            .collect(),
+    )
+}
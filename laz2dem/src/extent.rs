@@ -0,0 +1,309 @@
+use crate::options::Options;
+use geo::{BoundingRect, Coord, Intersects, MapCoords, MultiPolygon, Rect};
+use image::{Rgba, RgbaImage};
+use lazcommon::area::read_area;
+use proj::Proj;
+use std::{
+    collections::HashSet,
+    fs::read_to_string,
+    io::{Read, stdin},
+    path::Path,
+};
+use tilemath::{bbox::BBox, tile::Tile};
+
+/// Area to render in CRS of the tile matrix set
+pub struct Extent {
+    pub bbox: BBox,
+    pub area: Option<MultiPolygon>,
+    pub supertiles: Option<Vec<Tile>>,
+}
+
+impl Extent {
+    pub fn new(options: &Options) -> Self {
+        let proj = options.bbox_crs.as_ref().map(|bbox_crs| {
+            Proj::new_known_crs(bbox_crs, &options.tile_matrix_set.crs, None)
+                .expect("Failed to create PROJ transformation")
+        });
+
+        let bbox = options.bbox.map(|bbox| {
+            proj.as_ref().map_or(bbox, |proj| {
+                proj.transform_bounds(bbox.min_x, bbox.min_y, bbox.max_x, bbox.max_y, 11)
+                    .unwrap()
+                    .into()
+            })
+        });
+
+        let area = options.area.as_ref().map(|path| {
+            let (area, format_crs) = read_area(&read_to_string(path).unwrap());
+
+            let Some(area_crs) = options.area_crs.as_deref().or(format_crs) else {
+                return area;
+            };
+
+            let proj = Proj::new_known_crs(area_crs, &options.tile_matrix_set.crs, None)
+                .expect("Failed to create PROJ transformation");
+
+            area.map_coords(|coord| {
+                let (x, y) = proj.convert((coord.x, coord.y)).unwrap();
+
+                Coord { x, y }
+            })
+        });
+
+        let area_bbox = area.as_ref().map(|area| {
+            let rect = area.bounding_rect().expect("Area is empty");
+
+            BBox::new(rect.min().x, rect.min().y, rect.max().x, rect.max().y)
+        });
+
+        let supertiles = options
+            .tiles
+            .as_ref()
+            .map(|path| read_supertiles(path, options.unit_zoom_level()));
+
+        let supertiles_bbox = supertiles.as_ref().map(|supertiles| {
+            supertiles
+                .iter()
+                .map(|tile| options.tile_matrix_set.bounds(tile))
+                .reduce(|a, b| {
+                    BBox::new(
+                        a.min_x.min(b.min_x),
+                        a.min_y.min(b.min_y),
+                        a.max_x.max(b.max_x),
+                        a.max_y.max(b.max_y),
+                    )
+                })
+                .expect("Tile list is empty")
+        });
+
+        let bbox = match (bbox.or(supertiles_bbox), area_bbox) {
+            (Some(bbox), Some(area_bbox)) => BBox::new(
+                bbox.min_x.max(area_bbox.min_x),
+                bbox.min_y.max(area_bbox.min_y),
+                bbox.max_x.min(area_bbox.max_x),
+                bbox.max_y.min(area_bbox.max_y),
+            ),
+            (Some(bbox), None) | (None, Some(bbox)) => bbox,
+            (None, None) => unreachable!("bbox, area or tiles is required"),
+        };
+
+        Self {
+            bbox,
+            area,
+            supertiles,
+        }
+    }
+
+    /// Tiles of unit zoom level to rasterize
+    pub fn supertiles(&self, options: &Options) -> Vec<Tile> {
+        self.supertiles.clone().unwrap_or_else(|| {
+            let tms = &options.tile_matrix_set;
+
+            tms.covered_tiles(&self.bbox, options.unit_zoom_level())
+                .filter(|tile| self.intersects(&tms.bounds(tile)))
+                .collect()
+        })
+    }
+
+    pub fn intersects(&self, bbox: &BBox) -> bool {
+        self.area
+            .as_ref()
+            .is_none_or(|area| area.intersects(&to_rect(bbox)))
+    }
+
+    /// Makes pixels of the image covering `bbox` transparent if they are outside of the area.
+    pub fn mask(&self, img: &mut RgbaImage, bbox: &BBox) {
+        let Some(ref area) = self.area else {
+            return;
+        };
+
+        let rect = to_rect(bbox);
+
+        let edges: Vec<_> = area
+            .iter()
+            .filter(|polygon| polygon.intersects(&rect))
+            .flat_map(|polygon| polygon.interiors().iter().chain([polygon.exterior()]))
+            .flat_map(|ring| ring.lines())
+            .filter(|line| line.start.y.min(line.end.y) <= bbox.max_y)
+            .filter(|line| line.start.y.max(line.end.y) >= bbox.min_y)
+            .collect();
+
+        let (width, height) = img.dimensions();
+
+        let mut crossings = Vec::new();
+
+        for row in 0..height {
+            let y = bbox.max_y - row as f64 * bbox.height() / height as f64;
+
+            crossings.clear();
+
+            // even-odd rule
+            crossings.extend(
+                edges
+                    .iter()
+                    .filter(|line| (line.start.y > y) != (line.end.y > y))
+                    .map(|line| {
+                        line.start.x
+                            + (y - line.start.y) * (line.end.x - line.start.x)
+                                / (line.end.y - line.start.y)
+                    }),
+            );
+
+            crossings.sort_by(f64::total_cmp);
+
+            for col in 0..width {
+                let x = bbox.min_x + col as f64 * bbox.width() / width as f64;
+
+                if crossings.iter().filter(|crossing| **crossing <= x).count() % 2 == 0 {
+                    img.put_pixel(col, row, Rgba([0, 0, 0, 0]));
+                }
+            }
+        }
+    }
+}
+
+fn to_rect(bbox: &BBox) -> Rect {
+    Rect::new(
+        Coord {
+            x: bbox.min_x,
+            y: bbox.min_y,
+        },
+        Coord {
+            x: bbox.max_x,
+            y: bbox.max_y,
+        },
+    )
+}
+
+/// Highest zoom level of listed tiles; coordinates must fit in `u32`
+const MAX_ZOOM: u8 = 31;
+
+/// Tiles this many zoom levels below unit zoom level cover 65536 supertiles; lower ones are rejected
+const MAX_ZOOM_BELOW_UNIT: u8 = 8;
+
+/// Reads `z/x/y` tiles and maps them to covering tiles of unit zoom level
+fn read_supertiles(path: &Path, unit_zoom_level: u8) -> Vec<Tile> {
+    let mut content = String::new();
+
+    if path == Path::new("-") {
+        stdin().read_to_string(&mut content).unwrap();
+    } else {
+        content = read_to_string(path).unwrap();
+    }
+
+    parse_supertiles(&content, unit_zoom_level)
+}
+
+fn parse_supertiles(content: &str, unit_zoom_level: u8) -> Vec<Tile> {
+    let mut supertiles = HashSet::new();
+
+    for line in content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        let parsed: Result<Vec<u32>, _> = line.split('/').map(str::parse).collect();
+
+        let Ok([zoom, x, y]) = parsed.as_deref() else {
+            panic!("Invalid tile `{line}`, expected z/x/y");
+        };
+
+        let (x, y) = (*x, *y);
+
+        let zoom = u8::try_from(*zoom)
+            .ok()
+            .filter(|zoom| *zoom <= MAX_ZOOM)
+            .unwrap_or_else(|| panic!("Invalid zoom of tile `{line}`"));
+
+        if u64::from(x.max(y)) >= 1 << zoom {
+            panic!("Tile `{line}` is out of range of its zoom level");
+        }
+
+        if zoom + MAX_ZOOM_BELOW_UNIT < unit_zoom_level {
+            panic!(
+                "Tile `{line}` covers too many supertiles; list tiles of zoom {} or higher",
+                unit_zoom_level - MAX_ZOOM_BELOW_UNIT
+            );
+        }
+
+        if zoom >= unit_zoom_level {
+            let shift = zoom - unit_zoom_level;
+
+            supertiles.insert(Tile {
+                x: x >> shift,
+                y: y >> shift,
+                zoom: unit_zoom_level,
+            });
+        } else {
+            let shift = unit_zoom_level - zoom;
+
+            for dy in 0..1 << shift {
+                for dx in 0..1 << shift {
+                    supertiles.insert(Tile {
+                        x: (x << shift) + dx,
+                        y: (y << shift) + dy,
+                        zoom: unit_zoom_level,
+                    });
+                }
+            }
+        }
+    }
+
+    supertiles.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(tiles: Vec<Tile>) -> Vec<(u8, u32, u32)> {
+        let mut tiles: Vec<_> = tiles
+            .into_iter()
+            .map(|tile| (tile.zoom, tile.x, tile.y))
+            .collect();
+
+        tiles.sort();
+
+        tiles
+    }
+
+    #[test]
+    fn maps_finer_tiles_to_their_supertile() {
+        assert_eq!(
+            sorted(parse_supertiles("18/1000/2003\n18/1001/2002\n", 16)),
+            [(16, 250, 500)]
+        );
+    }
+
+    #[test]
+    fn expands_coarser_tiles_to_supertiles() {
+        assert_eq!(
+            sorted(parse_supertiles(" 15/3/4 \n\n", 16)),
+            [(16, 6, 8), (16, 6, 9), (16, 7, 8), (16, 7, 9)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "expected z/x/y")]
+    fn rejects_malformed_tile() {
+        parse_supertiles("16/1", 16);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid zoom")]
+    fn rejects_zoom_out_of_u8() {
+        parse_supertiles("272/0/0", 16);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn rejects_coordinates_out_of_zoom() {
+        parse_supertiles("2/4/0", 16);
+    }
+
+    #[test]
+    #[should_panic(expected = "too many supertiles")]
+    fn rejects_tiles_far_below_unit_zoom() {
+        parse_supertiles("0/0/0", 16);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::read::table_exists;
+use rusqlite::{Connection, OptionalExtension};
+use tilemath::bbox::BBox;
+
+/// Occupancy grid of file points stored by `lazindex --footprint-cell-size`
+pub struct Footprint {
+    cell_size: f64,
+    min_x: f64,
+    min_y: f64,
+    columns: u32,
+    rows: u32,
+    /// Bit per cell, row by row from south-west
+    cells: Vec<u8>,
+}
+
+impl Footprint {
+    /// Tells if any occupied cell intersects the bbox (in the projection of the file)
+    pub fn intersects(&self, bbox: &BBox) -> bool {
+        let range = |min: f64, max: f64, origin: f64, count: u32| {
+            let from = ((min - origin) / self.cell_size).floor();
+
+            let to = ((max - origin) / self.cell_size).floor();
+
+            (to >= 0.0 && from < f64::from(count))
+                .then(|| from.max(0.0) as u32..=(to as u32).min(count - 1))
+        };
+
+        let (Some(columns), Some(mut rows)) = (
+            range(bbox.min_x, bbox.max_x, self.min_x, self.columns),
+            range(bbox.min_y, bbox.max_y, self.min_y, self.rows),
+        ) else {
+            return false;
+        };
+
+        rows.any(|row| {
+            columns.clone().any(|column| {
+                let i = row as usize * self.columns as usize + column as usize;
+
+                self.cells[i >> 3] & (1 << (i & 7)) != 0
+            })
+        })
+    }
+}
+
+pub fn indexed_footprint(conn: &Connection, file: &str) -> Option<Footprint> {
+    if !table_exists(conn, "laz_footprint") {
+        return None;
+    }
+
+    conn.prepare_cached(
+        "SELECT cell_size, min_x, min_y, columns, rows, cells FROM laz_footprint WHERE file = ?1",
+    )
+    .unwrap()
+    .query_row([file], |row| {
+        Ok(Footprint {
+            cell_size: row.get(0)?,
+            min_x: row.get(1)?,
+            min_y: row.get(2)?,
+            columns: row.get(3)?,
+            rows: row.get(4)?,
+            cells: row.get(5)?,
+        })
+    })
+    .optional()
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 4 × 3 cells of 10 m from (100, 200) with occupied cells (column 2, row 1) and (column 0, row 2)
+    fn footprint() -> Footprint {
+        let mut cells = vec![0; 2];
+
+        for i in [4 + 2, 2 * 4] {
+            cells[i >> 3] |= 1 << (i & 7);
+        }
+
+        Footprint {
+            cell_size: 10.0,
+            min_x: 100.0,
+            min_y: 200.0,
+            columns: 4,
+            rows: 3,
+            cells,
+        }
+    }
+
+    #[test]
+    fn intersects_occupied_cell() {
+        assert!(footprint().intersects(&BBox::new(121.0, 211.0, 129.0, 219.0)));
+
+        assert!(footprint().intersects(&BBox::new(101.0, 221.0, 102.0, 222.0)));
+    }
+
+    #[test]
+    fn does_not_intersect_empty_cells() {
+        assert!(!footprint().intersects(&BBox::new(101.0, 201.0, 119.0, 219.0)));
+
+        assert!(!footprint().intersects(&BBox::new(131.0, 201.0, 139.0, 229.0)));
+    }
+
+    #[test]
+    fn clamps_bbox_to_grid() {
+        assert!(footprint().intersects(&BBox::new(0.0, 0.0, 1000.0, 1000.0)));
+
+        assert!(!footprint().intersects(&BBox::new(0.0, 0.0, 99.0, 1000.0)));
+
+        assert!(!footprint().intersects(&BBox::new(141.0, 0.0, 1000.0, 1000.0)));
+    }
+}
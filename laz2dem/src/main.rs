@@ -1,25 +1,76 @@
+mod chunks;
+mod extent;
+mod footprint;
+mod metadata;
 mod options;
+mod overview_cache;
 mod progress;
 mod rasterization;
 mod read;
 mod schema;
+mod seams;
 mod shading;
 mod shared_types;
+mod tms;
 
 use clap::Parser;
-use options::Options;
+use extent::Extent;
+use lazcommon::stop::install_stop_handler;
+use options::{ExistingFileAction, Options};
 use rasterization::rasterize;
-use read::read;
-use shared_types::Job;
+use read::{detect_source_projection, laz_tile_metadata};
+use seams::ensure_seamless_point_buffer;
+use shared_types::{Job, Source, TileMeta};
+use tilemath::tile::Tile;
 
 fn main() {
-    let options = Options::parse();
+    let mut options = Options::parse();
 
-    let tile_metas = read(&options);
+    // workers finish their current job and stop
+    install_stop_handler("Stopping after the current supertiles");
 
-    let mut jobs: Vec<_> = tile_metas.into_iter().map(Job::Rasterize).collect();
+    if options.min_zoom > options.zoom_level {
+        panic!("Min zoom must not be greater than zoom level.");
+    }
 
-    jobs.sort_by_cached_key(|job| job.tile().morton_code());
+    // listed tiles patch the existing output; continuing would render nothing and other actions would destroy it
+    if options.tiles.is_some()
+        && options
+            .existing_file_action
+            .is_some_and(|action| action != ExistingFileAction::Update)
+    {
+        panic!("--tiles can be used only with --existing-file-action update");
+    }
 
-    rasterize(&options, jobs);
+    match options.source() {
+        Source::LazIndexDb(path) if options.source_projection.is_none() => {
+            options.source_projection = detect_source_projection(&path);
+        }
+        Source::LazTileDb(path) => options.apply_laz_tile_metadata(&laz_tile_metadata(&path)),
+        Source::LazIndexDb(_) => {}
+    }
+
+    if options.seamless {
+        ensure_seamless_point_buffer(&mut options);
+    }
+
+    let extent = Extent::new(&options);
+
+    let mut supertiles = extent.supertiles(&options);
+
+    supertiles.sort_by_cached_key(Tile::morton_code);
+
+    let jobs: Vec<_> = match options.source() {
+        Source::LazTileDb(_) => supertiles
+            .into_iter()
+            .map(|tile| Job::Rasterize(TileMeta::new(tile, &options)))
+            .collect(),
+        // Morton order keeps batches compact
+        Source::LazIndexDb(_) => supertiles
+            .chunks(options.batch_size)
+            .map(|batch| Job::Read(batch.to_vec()))
+            .collect(),
+    };
+
+    rasterize(&options, &extent, jobs);
 }
@@ -0,0 +1,133 @@
+use crate::{
+    extent::Extent,
+    options::Options,
+    shared_types::{
+        IgorShadingParams, ObliqueShadingParams, ObliqueSlopeShadingParams, Shading, ShadingMethod,
+        Source,
+    },
+};
+use proj::Proj;
+use serde_json::{Value, json};
+
+/// Builds MBTiles metadata entries including the render parameters so that the output is self-describing.
+pub fn metadata(options: &Options, extent: &Extent) -> Vec<(String, String)> {
+    let to_4326 = Proj::new_known_crs(&options.tile_matrix_set.crs, "EPSG:4326", None)
+        .expect("Failed to create PROJ transformation");
+
+    // edges of the bbox are densified as they are curved in other projections than Web Mercator
+    let [min_lon, min_lat, max_lon, max_lat] = to_4326
+        .transform_bounds(
+            extent.bbox.min_x,
+            extent.bbox.min_y,
+            extent.bbox.max_x,
+            extent.bbox.max_y,
+            11,
+        )
+        .unwrap();
+
+    let mut meta = vec![
+        ("name".to_owned(), options.name.clone()),
+        ("minzoom".to_owned(), options.min_zoom.to_string()),
+        ("maxzoom".to_owned(), options.zoom_level.to_string()),
+        ("format".to_owned(), options.format.to_string()),
+        (
+            "bounds".to_owned(),
+            format!("{min_lon},{min_lat},{max_lon},{max_lat}"),
+        ),
+    ];
+
+    for (name, value) in [
+        ("description", &options.description),
+        ("attribution", &options.attribution),
+        ("version", &options.version),
+        (
+            "type",
+            &options
+                .layer_type
+                .as_ref()
+                .map(|layer_type| layer_type.to_string()),
+        ),
+    ] {
+        if let Some(value) = value {
+            meta.push((name.to_owned(), value.clone()));
+        }
+    }
+
+    meta.push((
+        "tile_matrix_set".to_owned(),
+        options
+            .tile_matrix_set
+            .to_json(options.tile_size, options.min_zoom, options.zoom_level)
+            .to_string(),
+    ));
+
+    meta.push((
+        "render_parameters".to_owned(),
+        render_parameters(options, extent).to_string(),
+    ));
+
+    // extra entries may override the ones above
+    for (name, value) in &options.metadata {
+        meta.retain(|(existing_name, _)| existing_name != name);
+
+        meta.push((name.clone(), value.clone()));
+    }
+
+    meta
+}
+
+fn render_parameters(options: &Options, extent: &Extent) -> Value {
+    let (source_type, source_path) = match options.source() {
+        Source::LazTileDb(path) => ("laz_tile_db", path),
+        Source::LazIndexDb(path) => ("laz_index_db", path),
+    };
+
+    json!({
+        "source": {
+            "type": source_type,
+            "path": source_path.to_string_lossy(),
+            "projection": options.source_projection,
+            "classes": [2],
+            "vertical_crs": options.source_vertical_crs,
+        },
+        "vertical_crs": options.target_vertical_crs,
+        "tile_matrix_set": options.tile_matrix_set.id,
+        "crs": options.tile_matrix_set.crs,
+        "bbox": <[f64; 4]>::from(extent.bbox),
+        "zoom_level": options.zoom_level,
+        "unit_zoom_level": options.unit_zoom_level(),
+        "tile_size": options.tile_size,
+        "buffer": options.buffer,
+        "z_factor": options.z_factor,
+        "contrast": options.contrast,
+        "brightness": options.brightness,
+        "shadings": options.shadings.0.iter().map(shading_to_json).collect::<Vec<_>>(),
+    })
+}
+
+fn shading_to_json(shading: &Shading) -> Value {
+    let color = format!("{:08X}", shading.color);
+
+    match &shading.method {
+        ShadingMethod::Igor(IgorShadingParams { azimuth }) => json!({
+            "method": "igor",
+            "color": color,
+            "azimuth": azimuth.to_degrees(),
+        }),
+        ShadingMethod::Oblique(ObliqueShadingParams { azimuth, altitude }) => json!({
+            "method": "oblique",
+            "color": color,
+            "azimuth": azimuth.to_degrees(),
+            "altitude": altitude.to_degrees(),
+        }),
+        ShadingMethod::IgorSlope => json!({
+            "method": "igor-slope",
+            "color": color,
+        }),
+        ShadingMethod::ObliqueSlope(ObliqueSlopeShadingParams { altitude }) => json!({
+            "method": "oblique-slope",
+            "color": color,
+            "altitude": altitude.to_degrees(),
+        }),
+    }
+}
@@ -1,12 +1,16 @@
-use crate::shared_types::{Shadings, Source};
+use crate::{
+    shared_types::{Shadings, Source},
+    tms::TileMatrixSet,
+};
 use clap::{ArgGroup, Parser, ValueEnum};
 use std::{
+    collections::HashMap,
     fmt::{Display, Formatter},
     num::ParseIntError,
     path::PathBuf,
     str::FromStr,
 };
-use tilemath::{bbox::BBox, constants::WEB_MERCATOR_EXTENT};
+use tilemath::bbox::BBox;
 
 #[derive(Clone, Debug, Parser, PartialEq)]
 #[clap(group = ArgGroup::new("exclusive").required(true))]
@@ -22,22 +26,73 @@ pub struct Options {
     #[clap(long, group = "exclusive")]
     pub laz_index_db: Option<PathBuf>,
 
-    /// EPSG:3857 bounding box to render
+    /// Bounding box to render
+    #[clap(long, required_unless_present_any = ["area", "tiles"])]
+    pub bbox: Option<BBox>,
+
+    /// GeoJSON or WKT file with polygons of the area to render, eg. a country border.
+    /// Only supertiles intersecting it are rendered. If `--bbox` is specified too then the area is clipped by it.
     #[clap(long)]
-    pub bbox: BBox,
+    pub area: Option<PathBuf>,
+
+    /// File with tiles to re-render in the existing output, one `z/x/y` per line; use `-` for stdin.
+    /// Their ancestor overviews are regenerated. Existing file action must be `update` (the default).
+    #[clap(long, conflicts_with_all = ["bbox", "area"])]
+    pub tiles: Option<PathBuf>,
 
-    /// Projection of points if reading from *.laz; default is EPSG:3857
+    /// Make pixels outside of `--area` transparent
+    #[clap(long, requires = "area")]
+    pub mask: bool,
+
+    /// Projection of `--bbox`, eg. EPSG:4326 or EPSG:5514; default is CRS of the tile matrix set
+    #[clap(long)]
+    pub bbox_crs: Option<String>,
+
+    /// Projection of `--area`; default is EPSG:4326 for GeoJSON and CRS of the tile matrix set for WKT
+    #[clap(long, requires = "area")]
+    pub area_crs: Option<String>,
+
+    /// Projection of points if reading from *.laz; default is CRS of the files stored in LAZ index which must all have the same. If LAZ index was created with `--mercator` then it applies only to files without projection
     #[clap(long, conflicts_with = "laz_tile_db")]
     pub source_projection: Option<String>,
 
+    /// Vertical CRS of heights if reading from *.laz, eg. EPSG:8357 (Baltic 1957 height).
+    /// Horizontal projection must be an EPSG code. PROJ must have needed geoid grids available locally.
+    #[clap(long, conflicts_with = "laz_tile_db", requires = "target_vertical_crs")]
+    pub source_vertical_crs: Option<String>,
+
+    /// Vertical CRS to transform heights to, eg. EPSG:3855 (EGM2008 height)
+    #[clap(long, requires = "source_vertical_crs")]
+    pub target_vertical_crs: Option<String>,
+
+    /// Tile matrix set of the output: `WebMercatorQuad`, `EuropeanETRS89_LAEAQuad` or a quadtree
+    /// defined as <crs>,<origin_x>,<origin_y>,<extent> where origin is the top left corner and extent
+    /// is the width of the single tile of zoom 0 in meters, eg. `EPSG:5514,-951499,-935499,409600`.
+    /// Other than `WebMercatorQuad` requires LAZ index.
+    #[clap(
+        long,
+        default_value = "WebMercatorQuad",
+        conflicts_with = "laz_tile_db"
+    )]
+    pub tile_matrix_set: TileMatrixSet,
+
     /// Max zoom level of tiles to generate
     #[clap(long)]
     pub zoom_level: u8,
 
-    /// If LAZ tile DB is used then use value of `--zoom-level` argument of `laztile`
-    /// If LAZ index is used then use zoom level to determine size of tile to process at once.
+    /// Min zoom level of overview tiles to generate
+    #[clap(long, default_value_t = 0)]
+    pub min_zoom: u8,
+
+    /// If LAZ tile DB is used then it must be the value of `--zoom-level` argument of `laztile`; default is read from the DB.
+    /// If LAZ index is used then use zoom level to determine size of tile to process at once; default is 16.
+    #[clap(long)]
+    pub unit_zoom_level: Option<u8>,
+
+    /// If LAZ index is used then number of supertiles to read points for at once.
+    /// Bigger batches read *.laz files less times but need more memory.
     #[clap(long, default_value_t = 16)]
-    pub unit_zoom_level: u8,
+    pub batch_size: usize,
 
     /// Shadings; `+` separated componets of shading. Shading component is <method>,method_param1[,method_param2...].
     /// ‎
@@ -68,6 +123,20 @@ pub struct Options {
     #[clap(long, default_value_t = 40)]
     pub buffer: u32,
 
+    /// Buffer in meters of points around supertile used for the triangulation; default is `--buffer` converted to meters.
+    /// If LAZ tile DB is used then default is value of `--buffer` argument of `laztile` read from the DB.
+    #[clap(long)]
+    pub point_buffer: Option<f64>,
+
+    /// Guarantee seamless supertile edges. Point buffer is increased to 10 average point spacings before rendering
+    /// (fails if LAZ tile DB has smaller buffer) and elevation differences along edges of adjacent supertiles are reported.
+    #[clap(long)]
+    pub seamless: bool,
+
+    /// Elevation difference in meters along supertile edges to report in seamless mode
+    #[clap(long, default_value_t = 0.01)]
+    pub seam_tolerance: f64,
+
     /// Tile image format. For alpha (transparency) support use `png`.
     #[clap(long, value_enum, default_value_t = Format::JPEG)]
     pub format: Format,
@@ -80,13 +149,106 @@ pub struct Options {
     #[clap(long, default_value = "FFFFFF")]
     pub background_color: Rgb,
 
+    /// Max memory in MiB for rendered tiles waiting for their overview; tiles over the limit are re-read from the output
+    #[clap(long)]
+    pub overview_cache_limit: Option<usize>,
+
     #[clap(long, value_enum)]
     pub existing_file_action: Option<ExistingFileAction>,
+
+    /// Tileset name written to the MBTiles metadata
+    #[clap(long, default_value = "Hillshade")]
+    pub name: String,
+
+    /// Tileset description written to the MBTiles metadata
+    #[clap(long)]
+    pub description: Option<String>,
+
+    /// Attribution (HTML) written to the MBTiles metadata
+    #[clap(long)]
+    pub attribution: Option<String>,
+
+    /// Tileset version written to the MBTiles metadata
+    #[clap(long)]
+    pub version: Option<String>,
+
+    /// Layer type written to the MBTiles metadata
+    #[clap(long = "type", value_enum)]
+    pub layer_type: Option<LayerType>,
+
+    /// Extra MBTiles metadata entry as <name>=<value>. Can be specified multiple times.
+    #[clap(long = "metadata", value_parser = parse_metadata_entry)]
+    pub metadata: Vec<(String, String)>,
 }
 
 impl Options {
     pub fn pixels_per_meter(&self) -> f64 {
-        (((self.tile_size as u64) << self.zoom_level) as f64) / 2.0 / WEB_MERCATOR_EXTENT
+        (((self.tile_size as u64) << self.zoom_level) as f64) / self.tile_matrix_set.extent
+    }
+
+    pub fn buffer_m(&self) -> f64 {
+        self.buffer as f64 / self.pixels_per_meter()
+    }
+
+    pub fn point_buffer_m(&self) -> f64 {
+        self.point_buffer.map_or_else(
+            || self.buffer_m(),
+            |point_buffer| point_buffer.max(self.buffer_m()),
+        )
+    }
+
+    pub fn unit_zoom_level(&self) -> u8 {
+        self.unit_zoom_level.unwrap_or(16)
+    }
+
+    pub fn supertile_zoom_offset(&self) -> u8 {
+        self.zoom_level - self.unit_zoom_level()
+    }
+
+    pub fn supertile_size(&self) -> u16 {
+        self.tile_size << self.supertile_zoom_offset()
+    }
+
+    /// Defaults unit zoom level and point buffer to the parameters of LAZ tile DB and validates them against it
+    pub fn apply_laz_tile_metadata(&mut self, metadata: &HashMap<String, String>) {
+        if let Some(zoom_level) = metadata.get("zoom_level") {
+            let zoom_level = zoom_level
+                .parse()
+                .expect("Invalid zoom level in LAZ tile DB");
+
+            match self.unit_zoom_level {
+                Some(unit_zoom_level) if unit_zoom_level != zoom_level => panic!(
+                    "Unit zoom level {unit_zoom_level} does not match zoom level {zoom_level} of LAZ tile DB"
+                ),
+                _ => self.unit_zoom_level = Some(zoom_level),
+            }
+        }
+
+        if let Some(buffer) = metadata.get("buffer") {
+            let buffer: f64 = buffer.parse().expect("Invalid buffer in LAZ tile DB");
+
+            self.point_buffer.get_or_insert(buffer);
+
+            // tiles have no points further away
+            if self.point_buffer_m() > buffer {
+                panic!(
+                    "Point buffer {} m exceeds buffer {buffer} m of LAZ tile DB; decrease --buffer or --point-buffer",
+                    self.point_buffer_m()
+                );
+            }
+        }
+
+        self.source_projection = metadata.get("source_projection").cloned();
+
+        self.source_vertical_crs = metadata.get("source_vertical_crs").cloned();
+
+        self.target_vertical_crs = metadata.get("vertical_crs").cloned();
+    }
+
+    /// Tile list is rendered into the existing output unless specified otherwise
+    pub fn existing_file_action(&self) -> Option<ExistingFileAction> {
+        self.existing_file_action
+            .or_else(|| self.tiles.as_ref().map(|_| ExistingFileAction::Update))
     }
 
     pub fn source(&self) -> Source {
@@ -105,6 +267,15 @@ impl Options {
 pub enum ExistingFileAction {
     Overwrite,
     Continue,
+    /// Replace rendered tiles and their overviews, keep the others
+    Update,
+}
+
+fn parse_metadata_entry(entry: &str) -> Result<(String, String), String> {
+    entry
+        .split_once('=')
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .ok_or_else(|| format!("missing `=` in `{entry}`"))
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -140,3 +311,22 @@ impl Display for Format {
         )
     }
 }
+
+#[derive(ValueEnum, Debug, Clone, PartialEq)]
+pub enum LayerType {
+    Overlay,
+    Baselayer,
+}
+
+impl Display for LayerType {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{}",
+            match self {
+                LayerType::Overlay => "overlay",
+                LayerType::Baselayer => "baselayer",
+            }
+        )
+    }
+}
@@ -0,0 +1,177 @@
+use image::RgbaImage;
+use std::collections::{HashMap, VecDeque};
+use tilemath::tile::Tile;
+
+pub enum ChildImage {
+    Loaded(RgbaImage),
+    Stored, // spilled or already present in the output; must be read from there
+}
+
+/// Images of finished tiles waiting for their parent overview.
+///
+/// If the memory limit is exceeded then the oldest images are spilled.
+/// They are already saved in the output so they are read back from there when the overview is built.
+pub struct OverviewCache {
+    images: HashMap<Tile, ChildImage>,
+    order: VecDeque<Tile>,
+    size: usize,
+    limit: Option<usize>,
+}
+
+impl OverviewCache {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            images: HashMap::new(),
+            order: VecDeque::new(),
+            size: 0,
+            limit,
+        }
+    }
+
+    /// Size of images held in memory, in bytes
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn insert(&mut self, tile: Tile, img: RgbaImage) {
+        self.size += img.as_raw().len();
+
+        // re-rendered tile replaces its previous image
+        if let Some(ChildImage::Loaded(previous)) =
+            self.images.insert(tile, ChildImage::Loaded(img))
+        {
+            self.size -= previous.as_raw().len();
+        }
+
+        let Some(limit) = self.limit else {
+            return;
+        };
+
+        self.order.push_back(tile);
+
+        while self.size > limit {
+            let Some(tile) = self.order.pop_front() else {
+                break;
+            };
+
+            if let Some(ChildImage::Loaded(img)) = self.images.get(&tile) {
+                self.size -= img.as_raw().len();
+
+                self.images.insert(tile, ChildImage::Stored);
+            }
+        }
+
+        // forget tiles which are not held in memory anymore
+        if self.order.len() > (self.images.len() << 1) + 1024 {
+            self.order
+                .retain(|tile| matches!(self.images.get(tile), Some(ChildImage::Loaded(_))));
+        }
+    }
+
+    pub fn insert_stored(&mut self, tile: Tile) {
+        self.images.insert(tile, ChildImage::Stored);
+    }
+
+    pub fn remove(&mut self, tile: &Tile) -> Option<ChildImage> {
+        let child_image = self.images.remove(tile);
+
+        if let Some(ChildImage::Loaded(ref img)) = child_image {
+            self.size -= img.as_raw().len();
+        }
+
+        child_image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMAGE_SIZE: usize = 16 * 16 * 4;
+
+    fn tile(x: u32) -> Tile {
+        Tile { x, y: 0, zoom: 10 }
+    }
+
+    fn image() -> RgbaImage {
+        RgbaImage::new(16, 16)
+    }
+
+    #[test]
+    fn spills_oldest_images_over_limit() {
+        let mut cache = OverviewCache::new(Some(2 * IMAGE_SIZE));
+
+        for x in 0..3 {
+            cache.insert(tile(x), image());
+        }
+
+        assert_eq!(cache.size(), 2 * IMAGE_SIZE);
+
+        assert!(matches!(cache.remove(&tile(0)), Some(ChildImage::Stored)));
+
+        assert!(matches!(
+            cache.remove(&tile(1)),
+            Some(ChildImage::Loaded(_))
+        ));
+
+        assert!(matches!(
+            cache.remove(&tile(2)),
+            Some(ChildImage::Loaded(_))
+        ));
+
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn keeps_all_images_without_limit() {
+        let mut cache = OverviewCache::new(None);
+
+        for x in 0..100 {
+            cache.insert(tile(x), image());
+        }
+
+        assert_eq!(cache.size(), 100 * IMAGE_SIZE);
+
+        assert!(matches!(
+            cache.remove(&tile(0)),
+            Some(ChildImage::Loaded(_))
+        ));
+    }
+
+    #[test]
+    fn removing_spilled_image_keeps_size() {
+        let mut cache = OverviewCache::new(Some(IMAGE_SIZE));
+
+        cache.insert(tile(0), image());
+
+        cache.insert(tile(1), image());
+
+        assert!(matches!(cache.remove(&tile(0)), Some(ChildImage::Stored)));
+
+        assert_eq!(cache.size(), IMAGE_SIZE);
+    }
+
+    #[test]
+    fn reinserted_image_is_counted_once() {
+        let mut cache = OverviewCache::new(None);
+
+        cache.insert(tile(0), image());
+
+        cache.insert(tile(0), image());
+
+        assert_eq!(cache.size(), IMAGE_SIZE);
+    }
+
+    #[test]
+    fn stored_tile_is_not_counted() {
+        let mut cache = OverviewCache::new(Some(IMAGE_SIZE));
+
+        cache.insert_stored(tile(0));
+
+        assert_eq!(cache.size(), 0);
+
+        assert!(matches!(cache.remove(&tile(0)), Some(ChildImage::Stored)));
+
+        assert!(cache.remove(&tile(0)).is_none());
+    }
+}
@@ -1,6 +1,7 @@
-use crate::shared_types::Job;
+use crate::shared_types::{Job, TileMeta};
 use std::{
     collections::{HashMap, HashSet},
+    sync::{Arc, Condvar},
     time::{Duration, SystemTime},
 };
 use tilemath::tile::Tile;
@@ -14,17 +15,28 @@ enum State {
 
 pub struct Progress {
     supertile_zoom_offset: u8,
-    pub jobs: Vec<Job>,
+    jobs: Vec<Job>,
     states: HashMap<Tile, State>,
+    active: usize, // jobs being processed
+    reading: bool, // batch of points is being read
     last_log: SystemTime,
     done_count: usize,
+    overview_cache_size: usize,
+    /// Notified when a job is added or finished
+    changed: Arc<Condvar>,
 }
 
 impl Progress {
-    pub fn new(jobs: Vec<Job>, supertile_zoom_offset: u8) -> Self {
+    pub fn new(
+        jobs: Vec<Job>,
+        supertile_zoom_offset: u8,
+        min_zoom: u8,
+        changed: Arc<Condvar>,
+    ) -> Self {
         let mut states: HashMap<Tile, State> = jobs
             .iter()
-            .flat_map(|job| job.tile().descendants(supertile_zoom_offset))
+            .flat_map(|job| job.supertiles())
+            .flat_map(|tile| tile.descendants(supertile_zoom_offset))
             .map(|tile| (tile, State::Waiting))
             .collect();
 
@@ -33,7 +45,11 @@ impl Progress {
         loop {
             states.extend(next.iter().map(|tile| (*tile, State::Planned)));
 
-            next = next.iter().filter_map(|tile| tile.parent()).collect();
+            next = next
+                .iter()
+                .filter_map(|tile| tile.parent())
+                .filter(|tile| tile.zoom >= min_zoom)
+                .collect();
 
             if next.is_empty() {
                 break;
@@ -44,26 +60,67 @@ impl Progress {
             supertile_zoom_offset,
             jobs,
             states,
+            active: 0,
+            reading: false,
             last_log: SystemTime::now(),
             done_count: 0,
+            overview_cache_size: 0,
+            changed,
         }
     }
 
     pub fn next(&mut self) -> Option<Job> {
-        let job = self.jobs.pop();
+        // read next batch only after the previous one was read to bound the memory
+        let index = if self.reading {
+            self.jobs
+                .iter()
+                .rposition(|job| !matches!(job, Job::Read(_)))?
+        } else {
+            self.jobs.len().checked_sub(1)?
+        };
+
+        let job = self.jobs.remove(index);
 
-        if let Some(ref job) = job {
-            let tiles = match job {
-                Job::Rasterize(tile_meta) => tile_meta.tile.descendants(self.supertile_zoom_offset),
-                Job::Overview(tile) => vec![*tile],
-            };
+        let tiles = match job {
+            Job::Read(_) => {
+                self.reading = true;
 
-            for tile in tiles {
-                *self.states.get_mut(&tile).unwrap() = State::Processing;
+                vec![]
             }
+            Job::Rasterize(ref tile_meta) => tile_meta.tile.descendants(self.supertile_zoom_offset),
+            Job::Overview(tile) => vec![tile],
+        };
+
+        for tile in tiles {
+            *self.states.get_mut(&tile).unwrap() = State::Processing;
         }
 
-        job
+        self.active += 1;
+
+        Some(job)
+    }
+
+    pub fn read(&mut self, tile_metas: Vec<TileMeta>) {
+        self.reading = false;
+
+        self.jobs.extend(tile_metas.into_iter().map(Job::Rasterize));
+
+        self.changed.notify_all();
+    }
+
+    pub fn finish_job(&mut self) {
+        self.active -= 1;
+
+        self.changed.notify_all();
+    }
+
+    /// No job is processed so no new job can be planned
+    pub const fn is_idle(&self) -> bool {
+        self.active == 0
+    }
+
+    pub const fn set_overview_cache_size(&mut self, size: usize) {
+        self.overview_cache_size = size;
     }
 
     pub fn done(&mut self, tile: Tile) {
@@ -90,6 +147,8 @@ impl Progress {
 
         self.jobs.push(Job::Overview(parent));
 
+        self.changed.notify_all();
+
         let t = SystemTime::now();
 
         self.done_count += 1;
@@ -98,10 +157,11 @@ impl Progress {
             self.last_log = t;
 
             println!(
-                "{}% {} {}",
+                "{}% {} {} {}MiB",
                 (self.done_count * 10_000 / self.states.len()) as f64 / 100.0,
                 self.jobs.len(),
                 self.done_count,
+                self.overview_cache_size >> 20,
             );
         }
     }
@@ -1,26 +1,30 @@
 use crate::{
+    extent::Extent,
+    metadata::metadata,
     options::{ExistingFileAction, Format, Options},
+    overview_cache::{ChildImage, OverviewCache},
     progress::Progress,
-    schema::create_schema,
+    read::read,
+    schema::{create_schema, is_deduplicated},
+    seams::SeamMonitor,
     shading::{compute_hillshade, shade},
     shared_types::{Job, PointWithHeight, Source},
 };
 use core::f64;
 use image::{
-    GenericImage, Pixel, Rgb, RgbImage, RgbaImage,
+    GenericImage, ImageFormat, Pixel, Rgb, RgbImage, RgbaImage,
     codecs::{jpeg::JpegEncoder, png::PngEncoder},
     imageops::{FilterType, crop_imm, resize},
     load_from_memory_with_format,
 };
 use las::Reader;
-use proj::Proj;
-use rusqlite::{Connection, Error, ErrorCode, OpenFlags};
+use lazcommon::stop::stopped;
+use rusqlite::{Connection, Error, OpenFlags};
 use spade::{DelaunayTriangulation, Point2, Triangulation};
 use std::{
-    collections::HashMap,
     fs::{exists, remove_file},
     io::Cursor,
-    sync::{Arc, Mutex},
+    sync::{Arc, Condvar, Mutex},
     thread::{self, available_parallelism},
 };
 use tilemath::tile::Tile;
@@ -31,67 +35,63 @@ const SELECT_TILE_EXISTS_SQL: &str =
 const SELECT_TILE_SQL: &str =
     "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3";
 
-const INSERT_TILE_SQL: &str = "INSERT INTO tiles VALUES (?1, ?2, ?3, ?4)";
+const INSERT_TILE_SQL: &str = "INSERT OR REPLACE INTO tiles VALUES (?1, ?2, ?3, ?4)";
+
+const INSERT_IMAGE_SQL: &str = "INSERT OR IGNORE INTO images (tile_data, tile_id) VALUES (?1, ?2)";
+
+const INSERT_MAP_SQL: &str = "INSERT OR REPLACE INTO map (zoom_level, tile_column, tile_row, tile_id) VALUES (?1, ?2, ?3, ?4)";
+
+const DELETE_TILE_SQL: &str =
+    "DELETE FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3";
+
+const DELETE_MAP_SQL: &str =
+    "DELETE FROM map WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3";
+
+const DELETE_UNUSED_IMAGES_SQL: &str =
+    "DELETE FROM images WHERE tile_id NOT IN (SELECT tile_id FROM map)";
 
 const SELECT_LAZTILE_SQL: &str = "SELECT data FROM tiles WHERE x = ?1 AND y = ?2";
 
-pub fn rasterize(options: &Options, jobs: Vec<Job>) {
+pub fn rasterize(options: &Options, extent: &Extent, jobs: Vec<Job>) {
     let output = &options.output;
 
-    let cont = exists(output).unwrap()
-        && match options.existing_file_action {
-            Some(ExistingFileAction::Overwrite) => {
-                remove_file(output).unwrap();
+    let existing_file_action = exists(output).unwrap().then(|| {
+        options
+            .existing_file_action()
+            .expect("Output file already exitsts. Specify --existing-file-action.")
+    });
+
+    if existing_file_action == Some(ExistingFileAction::Overwrite) {
+        remove_file(output).unwrap();
+    }
+
+    let cont = existing_file_action == Some(ExistingFileAction::Continue);
 
-                false
-            }
-            Some(ExistingFileAction::Continue) => true,
-            None => panic!("Output file already exitsts. Specify --existing-file-action."),
-        };
+    let update = existing_file_action == Some(ExistingFileAction::Update);
 
     let conn = Connection::open(output).unwrap();
 
-    {
-        let proj_3857_to_4326 = Proj::new_known_crs("EPSG:3857", "EPSG:4326", None)
-            .expect("Failed to create PROJ transformation");
-
-        let mut bounds = vec![
-            (options.bbox.min_x, options.bbox.min_y),
-            (options.bbox.max_x, options.bbox.max_y),
-        ];
-
-        proj_3857_to_4326.project_array(&mut bounds, false).unwrap();
-
-        if !cont {
-            create_schema(
-                &conn,
-                &[
-                    ("name", "Hillshade"), // TODO
-                    ("minzoom", "0"),
-                    ("maxzoom", options.zoom_level.to_string().as_ref()),
-                    ("format", &options.format.to_string()),
-                    (
-                        "bounds",
-                        &format!(
-                            "{},{},{},{}",
-                            bounds[0].0, bounds[0].1, bounds[1].0, bounds[1].1
-                        ),
-                    ),
-                ],
-            )
-            .unwrap();
-        }
+    if !cont && !update {
+        create_schema(&conn, &metadata(options, extent)).unwrap();
     }
 
+    // files created before deduplication was introduced have plain tiles table
+    let deduplicated = is_deduplicated(&conn).unwrap();
+
     conn.pragma_update(None, "synchronous", "OFF").unwrap();
 
     conn.pragma_update(None, "journal_mode", "WAL").unwrap();
 
     let conn = Arc::new(Mutex::new(conn));
 
+    // notified when jobs are added or finished so idle workers need not poll
+    let changed = Arc::new(Condvar::new());
+
     let state = Arc::new(Mutex::new(Progress::new(
         jobs,
-        options.zoom_level - options.unit_zoom_level,
+        options.supertile_zoom_offset(),
+        options.min_zoom,
+        Arc::clone(&changed),
     )));
 
     let laztile_conn = match options.source() {
@@ -101,24 +101,52 @@ pub fn rasterize(options: &Options, jobs: Vec<Job>) {
         Source::LazIndexDb(_) => None,
     };
 
-    let supertile_zoom_offset = options.zoom_level - options.unit_zoom_level;
+    let supertile_zoom_offset = options.supertile_zoom_offset();
 
-    thread::scope(|scope| {
-        let jobs_len = state.lock().unwrap().jobs.len();
+    // supertile is finished when its tiles of the lowest written zoom exist; overviews below min zoom aren't written
+    let done_marker_zoom_offset = options.min_zoom.saturating_sub(options.unit_zoom_level());
+
+    let seam_monitor = options
+        .seamless
+        .then(|| Arc::new(Mutex::new(SeamMonitor::new(options.seam_tolerance))));
 
-        let for_overviews = Arc::new(Mutex::new(HashMap::<Tile, RgbaImage>::new()));
+    thread::scope(|scope| {
+        let for_overviews = Arc::new(Mutex::new(OverviewCache::new(
+            options
+                .overview_cache_limit
+                .map(|limit_mib| limit_mib << 20),
+        )));
 
-        for _ in 0..(jobs_len.min(available_parallelism().unwrap().get())) {
+        for _ in 0..available_parallelism().unwrap().get() {
             let state = Arc::clone(&state);
 
+            let changed = Arc::clone(&changed);
+
             let conn = Arc::clone(&conn);
 
             let for_overviews = Arc::clone(&for_overviews);
 
             let laztile_conn = laztile_conn.clone();
 
+            let seam_monitor = seam_monitor.clone();
+
             scope.spawn(move || {
+                // tile has no data; previous one is removed when updating
+                let skip_tile = |tile: Tile| {
+                    if update {
+                        delete_tile(&conn.lock().unwrap(), tile, deduplicated).unwrap();
+                    }
+
+                    state.lock().unwrap().done(tile);
+                };
+
                 let save_tile = |tile: Tile, img: RgbaImage| {
+                    if img.pixels().all(|pixel| pixel[3] == 0) {
+                        skip_tile(tile);
+
+                        return;
+                    }
+
                     let mut buffer = vec![];
 
                     match options.format {
@@ -135,64 +163,122 @@ pub fn rasterize(options: &Options, jobs: Vec<Job>) {
                             .unwrap(),
                     }
 
-                    for_overviews.lock().unwrap().insert(tile, img);
+                    let overview_cache_size = {
+                        let mut for_overviews = for_overviews.lock().unwrap();
 
-                    let res = conn.lock().unwrap().execute(
-                        INSERT_TILE_SQL,
-                        (tile.zoom, tile.x, tile.reversed_y(), buffer),
-                    );
+                        for_overviews.insert(tile, img);
 
-                    match res {
-                        Err(Error::SqliteFailure(ref err, _))
-                            if err.code == ErrorCode::ConstraintViolation =>
-                        {
-                            println!("DUPLICATE");
-                        }
-                        _ => {
-                            res.unwrap();
+                        for_overviews.size()
+                    };
+
+                    insert_tile(&conn.lock().unwrap(), tile, &buffer, deduplicated).unwrap();
+
+                    let mut state = state.lock().unwrap();
+
+                    state.set_overview_cache_size(overview_cache_size);
+
+                    state.done(tile);
+                };
+
+                // all checked tiles were rendered by previous run; `tiles` are then marked done
+                let already_rendered = |checked: Vec<Tile>, tiles: Vec<Tile>| {
+                    if !cont {
+                        return false;
+                    }
+
+                    let conn = conn.lock().unwrap();
+
+                    let mut stmt = conn.prepare_cached(SELECT_TILE_EXISTS_SQL).unwrap();
+
+                    for tile in checked {
+                        if !stmt.exists((tile.zoom, tile.x, tile.reversed_y())).unwrap() {
+                            return false;
                         }
                     }
 
-                    state.lock().unwrap().done(tile);
+                    drop(stmt);
+
+                    drop(conn);
+
+                    for tile in tiles {
+                        for_overviews.lock().unwrap().insert_stored(tile);
+
+                        state.lock().unwrap().done(tile);
+                    }
+
+                    true
                 };
 
+                let mut processing = false;
+
                 loop {
-                    let Some(job) = state.lock().unwrap().next() else {
-                        break;
-                    };
+                    let mut progress = state.lock().unwrap();
 
-                    // println!("Processing {:?}", job);
+                    // previous job of this worker is finished
+                    if processing {
+                        progress.finish_job();
+                    }
+
+                    let job = loop {
+                        if stopped() {
+                            break None;
+                        }
+
+                        if let Some(job) = progress.next() {
+                            break Some(job);
+                        }
 
-                    if cont {
-                        let (tile, tiles) = match job {
-                            Job::Rasterize(ref tile_meta) => (
-                                tile_meta.tile,
-                                tile_meta.tile.descendants(supertile_zoom_offset),
-                            ),
-                            Job::Overview(tile) => (tile, vec![tile]),
-                        };
+                        // other threads may still produce jobs
+                        if progress.is_idle() {
+                            break None;
+                        }
 
-                        let conn = conn.lock().unwrap();
+                        progress = changed.wait(progress).unwrap();
+                    };
 
-                        let mut stmt = conn.prepare(SELECT_TILE_EXISTS_SQL).unwrap();
+                    drop(progress);
 
-                        let mut rows = stmt.query((tile.zoom, tile.x, tile.reversed_y())).unwrap();
+                    let Some(job) = job else {
+                        break;
+                    };
 
-                        if rows.next().unwrap().is_some() {
-                            for tile in tiles {
-                                for_overviews
-                                    .lock()
-                                    .unwrap()
-                                    .insert(tile, RgbaImage::default());
+                    processing = true;
 
-                                state.lock().unwrap().done(tile);
-                            }
+                    // println!("Processing {:?}", job);
 
-                            continue;
-                        }
+                    let skip = match job {
+                        Job::Read(_) => false,
+                        Job::Rasterize(ref tile_meta) => already_rendered(
+                            tile_meta.tile.descendants(done_marker_zoom_offset),
+                            tile_meta.tile.descendants(supertile_zoom_offset),
+                        ),
+                        Job::Overview(tile) => already_rendered(vec![tile], vec![tile]),
+                    };
+
+                    if skip {
+                        continue;
                     }
 
                     match job {
+                        Job::Read(supertiles) => {
+                            let supertiles: Vec<_> = supertiles
+                                .into_iter()
+                                .filter(|tile| {
+                                    !already_rendered(
+                                        tile.descendants(done_marker_zoom_offset),
+                                        tile.descendants(supertile_zoom_offset),
+                                    )
+                                })
+                                .collect();
+
+                            let tile_metas = if supertiles.is_empty() {
+                                vec![]
+                            } else {
+                                read(options, &supertiles)
+                            };
+
+                            state.lock().unwrap().read(tile_metas);
+                        }
                         Job::Rasterize(tile_meta) => {
                             let points = laztile_conn.as_ref().map_or_else(
                                 || tile_meta.points.into_inner().unwrap(),
@@ -229,7 +315,9 @@ pub fn rasterize(options: &Options, jobs: Vec<Job>) {
                             );
 
                             if points.is_empty() {
-                                state.lock().unwrap().done(tile_meta.tile);
+                                for tile in tile_meta.tile.descendants(supertile_zoom_offset) {
+                                    skip_tile(tile);
+                                }
 
                                 continue;
                             }
@@ -268,7 +356,17 @@ pub fn rasterize(options: &Options, jobs: Vec<Job>) {
                                 }
                             }
 
-                            let img = compute_hillshade(
+                            if let Some(ref seam_monitor) = seam_monitor {
+                                seam_monitor.lock().unwrap().record(
+                                    tile_meta.tile,
+                                    &img,
+                                    width_pixels as usize,
+                                    options.buffer as usize,
+                                    options.supertile_size() as usize,
+                                );
+                            }
+
+                            let mut img = compute_hillshade(
                                 &img,
                                 options.z_factor,
                                 height_pixels as usize,
@@ -284,6 +382,10 @@ pub fn rasterize(options: &Options, jobs: Vec<Job>) {
                                 },
                             );
 
+                            if options.mask {
+                                extent.mask(&mut img, &bbox);
+                            }
+
                             let mut tiles = tile_meta.tile.descendants(supertile_zoom_offset);
 
                             tiles.sort_by(|a, b| a.y.cmp(&b.y).then_with(|| a.x.cmp(&b.x)));
@@ -315,14 +417,18 @@ pub fn rasterize(options: &Options, jobs: Vec<Job>) {
                                 .into_iter()
                                 .enumerate()
                                 .filter_map(|(i, tile)| {
-                                    for_overviews.remove(&tile).map(|img| (i, tile, img))
+                                    for_overviews
+                                        .remove(&tile)
+                                        // when updating, not re-rendered children are read from the output
+                                        .or(update.then_some(ChildImage::Stored))
+                                        .map(|img| (i, tile, img))
                                 })
                                 .collect();
 
                             drop(for_overviews);
 
                             if imgs.is_empty() {
-                                state.lock().unwrap().done(tile);
+                                skip_tile(tile);
 
                                 continue;
                             }
@@ -332,30 +438,17 @@ pub fn rasterize(options: &Options, jobs: Vec<Job>) {
                                 u32::from(options.tile_size) << 1,
                             );
 
-                            for (i, tile, img) in imgs {
-                                let img = if cont && img.width() == 0 {
-                                    let data: Vec<u8> = {
-                                        let conn = conn.lock().unwrap();
-
-                                        let mut stmt = conn.prepare(SELECT_TILE_SQL).unwrap();
-
-                                        let mut rows = stmt
-                                            .query((tile.zoom, tile.x, tile.reversed_y()))
-                                            .unwrap();
-
-                                        let row = rows.next().unwrap().unwrap();
-
-                                        row.get(0).unwrap()
-                                    };
-
-                                    load_from_memory_with_format(
-                                        data.as_slice(),
-                                        image::ImageFormat::Jpeg, // TODO also support PNG
-                                    )
-                                    .unwrap()
-                                    .to_rgba8()
-                                } else {
-                                    img
+                            for (i, tile, child_image) in imgs {
+                                let img = match child_image {
+                                    ChildImage::Loaded(img) => img,
+                                    ChildImage::Stored => match load_tile(
+                                        &conn.lock().unwrap(),
+                                        tile,
+                                        &options.format,
+                                    ) {
+                                        Some(img) => img,
+                                        None => continue,
+                                    },
                                 };
 
                                 tile_img
@@ -381,6 +474,88 @@ pub fn rasterize(options: &Options, jobs: Vec<Job>) {
             });
         }
     });
+
+    // continuing would skip stale tiles which were not re-rendered yet
+    if stopped() {
+        if update && options.tiles.is_some() {
+            println!("Stopped; run the same --tiles command again to re-render the listed tiles");
+        } else if update {
+            println!("Stopped; run the same command again to re-render the rest");
+        } else {
+            println!("Stopped; run again with --existing-file-action continue to render the rest");
+        }
+    }
+
+    if let Some(seam_monitor) = seam_monitor {
+        seam_monitor.lock().unwrap().report();
+    }
+
+    // replaced tiles may leave unreferenced images
+    if deduplicated && (cont || update) {
+        conn.lock()
+            .unwrap()
+            .execute(DELETE_UNUSED_IMAGES_SQL, ())
+            .unwrap();
+    }
+}
+
+fn insert_tile(
+    conn: &Connection,
+    tile: Tile,
+    data: &[u8],
+    deduplicated: bool,
+) -> Result<(), Error> {
+    if !deduplicated {
+        conn.execute(
+            INSERT_TILE_SQL,
+            (tile.zoom, tile.x, tile.reversed_y(), data),
+        )?;
+
+        return Ok(());
+    }
+
+    let tile_id = format!("{:x}", md5::compute(data));
+
+    conn.execute(INSERT_IMAGE_SQL, (data, &tile_id))?;
+
+    conn.execute(
+        INSERT_MAP_SQL,
+        (tile.zoom, tile.x, tile.reversed_y(), &tile_id),
+    )?;
+
+    Ok(())
+}
+
+fn delete_tile(conn: &Connection, tile: Tile, deduplicated: bool) -> Result<(), Error> {
+    conn.execute(
+        if deduplicated {
+            DELETE_MAP_SQL
+        } else {
+            DELETE_TILE_SQL
+        },
+        (tile.zoom, tile.x, tile.reversed_y()),
+    )?;
+
+    Ok(())
+}
+
+fn load_tile(conn: &Connection, tile: Tile, format: &Format) -> Option<RgbaImage> {
+    let mut stmt = conn.prepare(SELECT_TILE_SQL).unwrap();
+
+    let mut rows = stmt.query((tile.zoom, tile.x, tile.reversed_y())).unwrap();
+
+    let data: Vec<u8> = rows.next().unwrap()?.get(0).unwrap();
+
+    let img = load_from_memory_with_format(
+        data.as_slice(),
+        match format {
+            Format::JPEG => ImageFormat::Jpeg,
+            Format::PNG => ImageFormat::Png,
+        },
+    )
+    .unwrap();
+
+    Some(img.to_rgba8())
 }
 
 fn rgba_to_rgb(img: &RgbaImage, background: Rgb<u8>) -> RgbImage {
@@ -1,120 +1,359 @@
 use crate::{
+    chunks::{copc_chunks, indexed_chunks},
+    footprint::indexed_footprint,
     options::Options,
     shared_types::{PointWithHeight, Source, TileMeta},
 };
 use core::f64;
 use las::{Reader, point::Classification};
 use proj::Proj;
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rusqlite::{Connection, OpenFlags};
 use spade::Point2;
-use std::sync::Mutex;
-use tilemath::{bbox::BBox, utils::bbox_covered_tiles};
-
-pub fn read(options: &Options) -> Vec<TileMeta> {
-    let buffer_m = options.buffer as f64 / options.pixels_per_meter();
-
-    let tile_metas: Vec<_> = bbox_covered_tiles(&options.bbox, options.unit_zoom_level)
-        .map(|tile| TileMeta {
-            tile,
-            bbox: tile
-                .bounds(options.tile_size << (options.zoom_level - options.unit_zoom_level))
-                .to_buffered(buffer_m),
-            points: Mutex::new(Vec::<PointWithHeight>::new()),
-        })
+use std::{collections::HashMap, path::Path};
+use tilemath::{bbox::BBox, tile::Tile};
+
+const BBOX_FILTER: &str = "laz_index.max_x >= ?1 AND laz_index.min_x <= ?3 AND laz_index.max_y >= ?2 AND laz_index.min_y <= ?4";
+
+const RTREE_BBOX_FILTER: &str = "laz_index.id IN (SELECT id FROM laz_rtree WHERE max_x >= ?1 AND min_x <= ?3 AND max_y >= ?2 AND min_y <= ?4)";
+
+const RTREE_3857_BBOX_FILTER: &str = "laz_index.id IN (SELECT id FROM laz_rtree_3857 WHERE max_x >= ?1 AND min_x <= ?3 AND max_y >= ?2 AND min_y <= ?4)";
+
+// files whose classes were counted are skipped if they have no ground points
+const GROUND_FILTER: &str = "(ground.point_count > 0 OR NOT EXISTS (SELECT 1 FROM laz_class_count WHERE laz_class_count.file = laz_index.file))";
+
+pub fn table_exists(conn: &Connection, name: &str) -> bool {
+    conn.prepare_cached("SELECT 1 FROM sqlite_master WHERE name = ?1")
+        .unwrap()
+        .exists([name])
+        .unwrap()
+}
+
+/// Query of files intersecting the bbox with their projection (if `mercator`) and ground point count (if known)
+fn select_files_sql(conn: &Connection, mercator: bool) -> String {
+    let has_rtree = mercator || table_exists(conn, "laz_rtree");
+
+    let has_id: bool = conn
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('laz_index') WHERE name = 'id'",
+            (),
+            |row| row.get(0),
+        )
+        .unwrap();
+
+    // R*Trees of older versions referred to `rowid` which VACUUM may change
+    if has_rtree && !has_id {
+        panic!("LAZ index has no stable file ids; update it with `lazindex --update`");
+    }
+
+    let bbox_filter = if mercator {
+        RTREE_3857_BBOX_FILTER
+    } else if has_rtree {
+        RTREE_BBOX_FILTER
+    } else {
+        BBOX_FILTER
+    };
+
+    let crs = if mercator { "laz_index.crs" } else { "NULL" };
+
+    if table_exists(conn, "laz_class_count") {
+        format!(
+            "SELECT laz_index.file, {crs}, ground.point_count FROM laz_index
+                LEFT JOIN laz_class_count AS ground ON ground.file = laz_index.file AND ground.class = 2
+                WHERE {bbox_filter} AND {GROUND_FILTER}"
+        )
+    } else {
+        format!("SELECT laz_index.file, {crs}, NULL FROM laz_index WHERE {bbox_filter}")
+    }
+}
+
+/// Reads parameters of tiles stored by `laztile`; empty if the DB was created by an older version.
+pub fn laz_tile_metadata(path: &Path) -> HashMap<String, String> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+
+    if !table_exists(&conn, "metadata") {
+        return HashMap::new();
+    }
+
+    conn.prepare("SELECT name, value FROM metadata")
+        .unwrap()
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect()
+}
+
+/// Reads the common projection of indexed files which `lazindex` read from their headers.
+///
+/// Returns `None` if the index has EPSG:3857 extents as every file is then transformed from its own projection.
+pub fn detect_source_projection(path: &Path) -> Option<String> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+
+    if table_exists(&conn, "laz_rtree_3857") {
+        return None;
+    }
+
+    let has_crs: bool = conn
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('laz_index') WHERE name = 'crs'",
+            (),
+            |row| row.get(0),
+        )
+        .unwrap();
+
+    if !has_crs {
+        panic!("LAZ index has no CRS of files; use --source-projection or re-create the index");
+    }
+
+    let crss: Vec<Option<String>> = conn
+        .prepare("SELECT DISTINCT crs FROM laz_index")
+        .unwrap()
+        .query_map((), |row| row.get(0))
+        .unwrap()
+        .map(|row| row.unwrap())
         .collect();
 
-    let Source::LazIndexDb(path) = options.source() else {
-        return tile_metas;
+    match crss.as_slice() {
+        [] => None,
+        [Some(crs)] => Some(crs.clone()),
+        _ if crss.contains(&None) => {
+            panic!("Some indexed files have no CRS; use --source-projection")
+        }
+        _ => panic!(
+            "Indexed files have different CRS; use --source-projection or re-create the index with `lazindex --mercator`"
+        ),
+    }
+}
+
+/// Adds vertical CRS to the horizontal one using PROJ `EPSG:<horizontal>+<vertical>` syntax
+fn compound_crs(horizontal: &str, vertical: Option<&str>) -> String {
+    let Some(vertical) = vertical else {
+        return horizontal.to_owned();
     };
 
-    let bbox_unprojected = options.source_projection.as_ref().map(|source_projection| {
-        let bbox_unprojected: BBox = Proj::new_known_crs("EPSG:3857", source_projection, None)
-            .expect("Failed to create PROJ transformation")
-            .transform_bounds(
-                options.bbox.min_x,
-                options.bbox.min_y,
-                options.bbox.max_x,
-                options.bbox.max_y,
-                11,
+    if !horizontal.starts_with("EPSG:") {
+        panic!("Vertical CRS can be combined only with EPSG code of horizontal projection");
+    }
+
+    format!(
+        "{horizontal}+{}",
+        vertical.strip_prefix("EPSG:").unwrap_or(vertical)
+    )
+}
+
+/// Transforms bbox between projections
+fn transform_bbox(from: &str, to: &str, bbox: &BBox) -> BBox {
+    Proj::new_known_crs(from, to, None)
+        .expect("Failed to create PROJ transformation")
+        .transform_bounds(bbox.min_x, bbox.min_y, bbox.max_x, bbox.max_y, 11)
+        .unwrap()
+        .into()
+}
+
+/// Reads points of the batch of supertiles from *.laz files referred by LAZ index
+pub fn read(options: &Options, supertiles: &[Tile]) -> Vec<TileMeta> {
+    let tile_metas: Vec<_> = supertiles
+        .iter()
+        .map(|tile| TileMeta::new(*tile, options))
+        .collect();
+
+    // points around the supertiles are needed so that edges match neighbours
+    let bbox = tile_metas
+        .iter()
+        .map(|tile_meta| tile_meta.points_bbox)
+        .reduce(|a, b| {
+            BBox::new(
+                a.min_x.min(b.min_x),
+                a.min_y.min(b.min_y),
+                a.max_x.max(b.max_x),
+                a.max_y.max(b.max_y),
             )
-            .unwrap()
-            .into();
+        })
+        .expect("Batch is empty");
+
+    // supertile coordinates to index in `tile_metas`
+    let tile_indices: HashMap<_, _> = tile_metas
+        .iter()
+        .enumerate()
+        .map(|(i, tile_meta)| ((tile_meta.tile.x, tile_meta.tile.y), i))
+        .collect();
+
+    let tms = &options.tile_matrix_set;
 
-        bbox_unprojected
-    });
+    let supertile_m = tms.tile_span(options.unit_zoom_level());
+
+    let point_buffer = options.point_buffer_m();
+
+    // range of supertile coordinates whose buffered bounds contain the coordinate (measured from the tile origin)
+    let tile_range = |from_origin: f64| {
+        let min = ((from_origin - point_buffer) / supertile_m)
+            .floor()
+            .max(0.0) as u32;
+
+        let max = ((from_origin + point_buffer) / supertile_m)
+            .floor()
+            .max(0.0) as u32;
+
+        min..=max
+    };
+
+    let Source::LazIndexDb(path) = options.source() else {
+        unreachable!("only LAZ index is read in batches");
+    };
 
     let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
 
-    let mut stmt = conn.prepare("SELECT file FROM laz_index WHERE max_x >= ?1 AND min_x <= ?3 AND max_y >= ?2 AND min_y <= ?4").unwrap();
+    // file extents in EPSG:3857 are queried directly and files may have different projections
+    let mercator = table_exists(&conn, "laz_rtree_3857");
+
+    let query_bbox = match (mercator, &options.source_projection) {
+        (true, _) if !tms.is_web_mercator() => transform_bbox(&tms.crs, "EPSG:3857", &bbox),
+        (false, Some(source_projection)) => transform_bbox(&tms.crs, source_projection, &bbox),
+        _ => bbox,
+    };
+
+    let mut stmt = conn.prepare(&select_files_sql(&conn, mercator)).unwrap();
 
     let rows = stmt
-        .query_map(
-            <[f64; 4]>::from(bbox_unprojected.unwrap_or_else(|| {
-                BBox::new(
-                    options.bbox.min_x,
-                    options.bbox.min_y,
-                    options.bbox.max_x,
-                    options.bbox.max_y,
-                )
-            })),
-            |row| row.get::<_, String>(0),
-        )
+        .query_map(<[f64; 4]>::from(query_bbox), |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, Option<u64>>(2)?,
+            ))
+        })
         .unwrap();
 
-    let files: Vec<String> = rows.map(|row| row.unwrap()).collect();
+    // `None` unless some of the files have their classes counted
+    let mut ground_point_count = None;
 
-    println!("Reading {} files", files.len());
+    let files: Vec<_> = rows
+        .map(|row| {
+            let (file, crs, count) = row.unwrap();
 
-    files.par_iter().for_each_init(
-        || {
-            options.source_projection.as_ref().map(|source_projection| {
-                Proj::new_known_crs("EPSG:3857", source_projection, None)
-                    .expect("Failed to create PROJ transformation")
-            })
-        },
-        |proj, file| {
-            println!("READ {file}");
+            if let Some(count) = count {
+                *ground_point_count.get_or_insert(0) += count;
+            }
 
-            let mut reader = Reader::from_path(file).unwrap();
+            let chunks = indexed_chunks(&conn, &file);
 
-            for point in reader.points() {
-                let point = point.unwrap();
+            let footprint = indexed_footprint(&conn, &file);
 
-                if point.classification != Classification::Ground {
-                    continue;
-                }
+            let projection = crs
+                .or_else(|| options.source_projection.clone())
+                .unwrap_or_else(|| panic!("File {file} has no CRS; use --source-projection"));
+
+            (file, projection, chunks, footprint)
+        })
+        .collect();
+
+    if let Some(ground_point_count) = ground_point_count {
+        println!(
+            "Reading {} files with {ground_point_count} ground points",
+            files.len()
+        );
+    } else {
+        println!("Reading {} files", files.len());
+    }
+
+    files.into_par_iter().for_each_init(
+        HashMap::new,
+        |projections, (file, projection, chunks, footprint)| {
+            // transformation to CRS of the tile matrix set and bbox in the projection of the file
+            let (proj, source_bbox) =
+                projections
+                    .entry(projection)
+                    .or_insert_with_key(|projection| {
+                        (
+                            Proj::new_known_crs(
+                                &compound_crs(projection, options.source_vertical_crs.as_deref()),
+                                &compound_crs(&tms.crs, options.target_vertical_crs.as_deref()),
+                                None,
+                            )
+                            .expect("Failed to create PROJ transformation"),
+                            transform_bbox(&tms.crs, projection, &bbox),
+                        )
+                    });
+
+            // header bounds may overstate the coverage
+            if let Some(footprint) = footprint
+                && !footprint.intersects(source_bbox)
+            {
+                println!("SKIP {file}");
 
-                if let Some(bbox_unprojected) = bbox_unprojected {
-                    if !bbox_unprojected.contains(point.x, point.y) {
+                return;
+            }
+
+            let mut reader = Reader::from_path(&file).unwrap();
+
+            // read only chunks intersecting the bbox if the file is COPC or its chunks are indexed
+            let ranges: Vec<_> =
+                match chunks.or_else(|| copc_chunks(Path::new(&file), reader.header())) {
+                    Some(chunks) => {
+                        let ranges: Vec<_> = chunks
+                            .iter()
+                            .filter(|chunk| chunk.intersects(source_bbox))
+                            .map(|chunk| (chunk.first_point, chunk.point_count))
+                            .collect();
+
+                        println!("READ {file} ({} of {} chunks)", ranges.len(), chunks.len());
+
+                        ranges
+                    }
+                    None => {
+                        println!("READ {file}");
+
+                        vec![(0, reader.header().number_of_points())]
+                    }
+                };
+
+            // collected per file to not contend for the locks of shared point vectors
+            let mut points: Vec<Vec<PointWithHeight>> =
+                tile_metas.iter().map(|_| Vec::new()).collect();
+
+            for (first_point, point_count) in ranges {
+                reader.seek(first_point).unwrap();
+
+                for point in reader.points().take(point_count as usize) {
+                    let point = point.unwrap();
+
+                    if point.classification != Classification::Ground {
                         continue;
                     }
-                }
 
-                let (x, y) = proj.as_ref().map_or_else(
-                    || (point.x, point.y),
-                    |proj| proj.convert((point.x, point.y)).unwrap(),
-                );
+                    if !source_bbox.contains(point.x, point.y) {
+                        continue;
+                    }
 
-                if !options.bbox.contains(x, y) {
-                    continue;
-                }
+                    let (x, y, z) = proj.convert((point.x, point.y, point.z)).unwrap();
 
-                for (i, tile_meta) in tile_metas.iter().enumerate() {
-                    if !tile_meta.bbox.contains(x, y) {
+                    if !bbox.contains(x, y) {
                         continue;
                     }
 
-                    tile_metas
-                        .get(i)
-                        .unwrap()
-                        .points
-                        .lock()
-                        .unwrap()
-                        .push(PointWithHeight {
-                            position: Point2::new(x, y),
-                            height: point.z,
-                        });
+                    for tile_y in tile_range(tms.origin_y - y) {
+                        for tile_x in tile_range(x - tms.origin_x) {
+                            let Some(&i) = tile_indices.get(&(tile_x, tile_y)) else {
+                                continue;
+                            };
+
+                            // floor rounding may include neighbour exactly at the edge
+                            if !tile_metas[i].points_bbox.contains(x, y) {
+                                continue;
+                            }
+
+                            points[i].push(PointWithHeight {
+                                position: Point2::new(x, y),
+                                height: z,
+                            });
+                        }
+                    }
+                }
+            }
+
+            for (tile_meta, points) in tile_metas.iter().zip(points) {
+                if !points.is_empty() {
+                    tile_meta.points.lock().unwrap().extend(points);
                 }
             }
 
@@ -1,6 +1,6 @@
-use rusqlite::{Connection, Error};
+use rusqlite::{Connection, Error, OptionalExtension};
 
-pub fn create_schema(conn: &Connection, meta: &[(&str, &str)]) -> Result<(), Error> {
+pub fn create_schema(conn: &Connection, meta: &[(String, String)]) -> Result<(), Error> {
     conn.execute(
         "CREATE TABLE metadata (
           name TEXT NOT NULL,
@@ -10,26 +10,57 @@ pub fn create_schema(conn: &Connection, meta: &[(&str, &str)]) -> Result<(), Err
         (),
     )?;
 
+    // identical tiles (eg. water, borders) share single image
     conn.execute(
-        "CREATE TABLE tiles (
+        "CREATE TABLE map (
           zoom_level INTEGER NOT NULL,
           tile_column INTEGER NOT NULL,
           tile_row INTEGER NOT NULL,
-          tile_data BLOB NOT NULL
+          tile_id TEXT NOT NULL
         )",
         (),
     )?;
 
     conn.execute(
-        "CREATE UNIQUE INDEX idx_tiles ON tiles (zoom_level, tile_column, tile_row)",
+        "CREATE UNIQUE INDEX map_index ON map (zoom_level, tile_column, tile_row)",
+        (),
+    )?;
+
+    conn.execute(
+        "CREATE TABLE images (
+          tile_data BLOB NOT NULL,
+          tile_id TEXT NOT NULL
+        )",
+        (),
+    )?;
+
+    conn.execute("CREATE UNIQUE INDEX images_id ON images (tile_id)", ())?;
+
+    conn.execute(
+        "CREATE VIEW tiles AS SELECT
+          map.zoom_level AS zoom_level,
+          map.tile_column AS tile_column,
+          map.tile_row AS tile_row,
+          images.tile_data AS tile_data
+        FROM map JOIN images ON images.tile_id = map.tile_id",
         (),
     )?;
 
     let mut stmt = conn.prepare("INSERT INTO metadata VALUES (?1, ?2)")?;
 
     for item in meta {
-        stmt.execute(*item)?;
+        stmt.execute((&item.0, &item.1))?;
     }
 
     Ok(())
 }
+
+pub fn is_deduplicated(conn: &Connection) -> Result<bool, Error> {
+    conn.query_row(
+        "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'map'",
+        (),
+        |_| Ok(()),
+    )
+    .optional()
+    .map(|row| row.is_some())
+}
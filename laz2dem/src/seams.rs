@@ -0,0 +1,273 @@
+use crate::{
+    options::Options,
+    read::{laz_tile_metadata, table_exists},
+    shared_types::Source,
+};
+use las::Reader;
+use rusqlite::{Connection, OpenFlags};
+use std::{collections::HashMap, io::Cursor, path::Path};
+use tilemath::tile::Tile;
+
+/// Minimal point buffer expressed in average point spacings to consider supertile edges seamless.
+///
+/// Delaunay triangles near the border of the point set are elongated and differ from the triangles
+/// the neighbour builds from its full point set. Within a few spacings from the border the triangulations
+/// agree again for regular data; the rest of the margin covers uneven density of overlapping strips and gaps.
+pub const SEAMLESS_SPACING_FACTOR: f64 = 10.0;
+
+/// Number of LAZ tiles whose point counts are used to estimate the point spacing
+const SPACING_SAMPLE_SIZE: usize = 256;
+
+/// Makes point buffer big enough for seamless supertile edges before anything is rendered.
+///
+/// LAZ index is read with the increased buffer; LAZ tile DB can't provide more points than its buffer.
+pub fn ensure_seamless_point_buffer(options: &mut Options) {
+    let spacing = match options.source() {
+        Source::LazIndexDb(path) => indexed_point_spacing(&path),
+        Source::LazTileDb(path) => tiled_point_spacing(
+            &path,
+            options.tile_matrix_set.tile_span(options.unit_zoom_level()),
+        ),
+    };
+
+    let Some(spacing) = spacing else {
+        panic!(
+            "Point spacing can't be estimated as the source has no points; seamless edges can't be ensured"
+        );
+    };
+
+    let required_buffer_m = SEAMLESS_SPACING_FACTOR * spacing;
+
+    if options.point_buffer_m() >= required_buffer_m {
+        return;
+    }
+
+    match options.source() {
+        Source::LazIndexDb(_) => {
+            println!(
+                "Point spacing {spacing:.2} m requires point buffer of {required_buffer_m:.1} m to be seamless; increasing it"
+            );
+
+            options.point_buffer = Some(required_buffer_m);
+        }
+        Source::LazTileDb(_) => panic!(
+            "Point spacing {spacing:.2} m requires point buffer of {required_buffer_m:.1} m to be seamless; re-create LAZ tile DB with bigger --buffer"
+        ),
+    }
+}
+
+/// Average ground point spacing of indexed files estimated from their extents
+fn indexed_point_spacing(path: &Path) -> Option<f64> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+
+    let has_class_counts = table_exists(&conn, "laz_class_count")
+        && conn
+            .query_row("SELECT EXISTS (SELECT 1 FROM laz_class_count)", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+
+    // total point count if classes were not counted
+    let sql = if has_class_counts {
+        "SELECT SUM((max_x - min_x) * (max_y - min_y)), SUM(ground.point_count) FROM laz_index
+            JOIN laz_class_count AS ground ON ground.file = laz_index.file AND ground.class = 2
+            WHERE ground.point_count > 0"
+    } else {
+        "SELECT SUM((max_x - min_x) * (max_y - min_y)), SUM(point_count) FROM laz_index"
+    };
+
+    let (area, count): (Option<f64>, Option<f64>) = conn
+        .query_row(sql, (), |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap();
+
+    let (area, count) = (area?, count?);
+
+    (count > 0.0).then(|| (area / count).sqrt())
+}
+
+/// Median ground point spacing of a sample of LAZ tiles
+fn tiled_point_spacing(path: &Path, tile_span: f64) -> Option<f64> {
+    let buffer: f64 = laz_tile_metadata(path)
+        .get("buffer")
+        .map_or(0.0, |buffer| buffer.parse().unwrap());
+
+    let tile_m = tile_span + 2.0 * buffer;
+
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT x, y, data FROM tiles
+                WHERE (x, y) IN (SELECT x, y FROM tiles GROUP BY x, y ORDER BY RANDOM() LIMIT ?1)",
+        )
+        .unwrap();
+
+    let mut rows = stmt.query([SPACING_SAMPLE_SIZE]).unwrap();
+
+    let mut counts = HashMap::<(u32, u32), u64>::new();
+
+    while let Some(row) = rows.next().unwrap() {
+        let data: Vec<u8> = row.get(2).unwrap();
+
+        let reader = Reader::new(Cursor::new(data)).unwrap();
+
+        *counts
+            .entry((row.get(0).unwrap(), row.get(1).unwrap()))
+            .or_default() += reader.header().number_of_points();
+    }
+
+    // tiles at the border of the data are covered only partially
+    let mut spacings: Vec<_> = counts
+        .into_values()
+        .filter(|count| *count > 0)
+        .map(|count| tile_m / (count as f64).sqrt())
+        .collect();
+
+    spacings.sort_by(f64::total_cmp);
+
+    spacings.get(spacings.len() / 2).copied()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Side {
+    West,
+    East,
+    North,
+    South,
+}
+
+/// Compares elevations which adjacent supertiles interpolated along their shared edge.
+///
+/// Both supertiles render the pixels around the edge (as part of their buffer) so they should match.
+/// The strip is 3 pixels wide as it is what the hillshading kernel at the edge reads.
+pub struct SeamMonitor {
+    strips: HashMap<(Tile, Side), Vec<f32>>,
+    tolerance: f64,
+    compared: usize,
+    exceeded: usize,
+    max: f64,
+}
+
+impl SeamMonitor {
+    pub fn new(tolerance: f64) -> Self {
+        Self {
+            strips: HashMap::new(),
+            tolerance,
+            compared: 0,
+            exceeded: 0,
+            max: 0.0,
+        }
+    }
+
+    /// Records edges of rendered supertile elevation (rows from south) and compares them with already rendered neighbours.
+    pub fn record(
+        &mut self,
+        tile: Tile,
+        elevation: &[f64],
+        width: usize,
+        buffer_px: usize,
+        core_px: usize,
+    ) {
+        // strips reach a pixel beyond the core on both sides
+        if buffer_px < 2 {
+            return;
+        }
+
+        let strip = |cols: [usize; 2], rows: [usize; 2]| -> Vec<f32> {
+            (rows[0]..=rows[1])
+                .flat_map(|y| (cols[0]..=cols[1]).map(move |x| elevation[y * width + x] as f32))
+                .collect()
+        };
+
+        let core = [buffer_px, buffer_px + core_px];
+
+        let low = [buffer_px - 1, buffer_px + 1];
+
+        let high = [buffer_px + core_px - 1, buffer_px + core_px + 1];
+
+        for (side, cols, rows) in [
+            (Side::West, low, core),
+            (Side::East, high, core),
+            (Side::South, core, low),
+            (Side::North, core, high),
+        ] {
+            let (neighbour, neighbour_side) = match side {
+                Side::West if tile.x > 0 => (
+                    Tile {
+                        x: tile.x - 1,
+                        ..tile
+                    },
+                    Side::East,
+                ),
+                Side::East => (
+                    Tile {
+                        x: tile.x + 1,
+                        ..tile
+                    },
+                    Side::West,
+                ),
+                Side::North if tile.y > 0 => (
+                    Tile {
+                        y: tile.y - 1,
+                        ..tile
+                    },
+                    Side::South,
+                ),
+                Side::South => (
+                    Tile {
+                        y: tile.y + 1,
+                        ..tile
+                    },
+                    Side::North,
+                ),
+                _ => continue,
+            };
+
+            let strip = strip(cols, rows);
+
+            match self.strips.remove(&(neighbour, neighbour_side)) {
+                Some(neighbour_strip) => self.compare(tile, side, &strip, &neighbour_strip),
+                None => {
+                    self.strips.insert((tile, side), strip);
+                }
+            }
+        }
+    }
+
+    fn compare(&mut self, tile: Tile, side: Side, strip: &[f32], neighbour_strip: &[f32]) {
+        let diffs: Vec<_> = strip
+            .iter()
+            .zip(neighbour_strip)
+            .filter(|(a, b)| !a.is_nan() && !b.is_nan())
+            .map(|(a, b)| f64::from((a - b).abs()))
+            .collect();
+
+        if diffs.is_empty() {
+            return;
+        }
+
+        let max = diffs.iter().copied().fold(0.0, f64::max);
+
+        let mean = diffs.iter().sum::<f64>() / diffs.len() as f64;
+
+        self.compared += 1;
+
+        self.max = self.max.max(max);
+
+        if max > self.tolerance {
+            self.exceeded += 1;
+
+            println!(
+                "SEAM {}/{}/{} {side:?} max {max:.3} m, mean {mean:.3} m",
+                tile.zoom, tile.x, tile.y
+            );
+        }
+    }
+
+    pub fn report(&self) {
+        println!(
+            "Compared {} supertile edges, {} exceed {} m, max difference {:.3} m",
+            self.compared, self.exceeded, self.tolerance, self.max
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::options::Options;
 use spade::{HasPosition, Point2};
 use std::{
     error::Error,
@@ -23,30 +24,51 @@ impl HasPosition for PointWithHeight {
 
 pub struct TileMeta {
     pub tile: Tile,
+    /// Rendered area including the buffer
     pub bbox: BBox,
+    /// Area of points used for the triangulation
+    pub points_bbox: BBox,
     pub points: Mutex<Vec<PointWithHeight>>,
 }
 
+impl TileMeta {
+    pub fn new(tile: Tile, options: &Options) -> Self {
+        let bounds = options.tile_matrix_set.bounds(&tile);
+
+        Self {
+            tile,
+            bbox: bounds.to_buffered(options.buffer_m()),
+            points_bbox: bounds.to_buffered(options.point_buffer_m()),
+            points: Mutex::new(Vec::new()),
+        }
+    }
+}
+
 impl Debug for TileMeta {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("TileMeta")
             .field("tile", &self.tile)
             .field("bbox", &self.bbox)
+            .field("points_bbox", &self.points_bbox)
             .finish()
     }
 }
 
 #[derive(Debug)]
 pub enum Job {
+    /// Read points of the batch of supertiles; produces `Rasterize` jobs
+    Read(Vec<Tile>),
     Rasterize(TileMeta),
     Overview(Tile),
 }
 
 impl Job {
-    pub const fn tile(&self) -> Tile {
+    /// Supertiles rendered by the job
+    pub fn supertiles(&self) -> Vec<Tile> {
         match self {
-            Self::Rasterize(tile_meta) => tile_meta.tile,
-            Self::Overview(tile) => *tile,
+            Self::Read(supertiles) => supertiles.clone(),
+            Self::Rasterize(tile_meta) => vec![tile_meta.tile],
+            Self::Overview(_) => vec![],
         }
     }
 }
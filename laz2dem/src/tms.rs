@@ -0,0 +1,316 @@
+use serde_json::{Value, json};
+use std::{error::Error, fmt::Display, str::FromStr};
+use tilemath::{bbox::BBox, constants::WEB_MERCATOR_EXTENT, tile::Tile};
+
+/// Quadtree tile matrix set; tile matrix of zoom `z` has `2^z` × `2^z` tiles and zoom 0 has single tile.
+///
+/// See <https://docs.ogc.org/is/17-083r4/17-083r4.html>. CRS units must be meters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileMatrixSet {
+    /// Identifier, eg. `WebMercatorQuad`
+    pub id: String,
+    pub crs: String,
+    /// Top left corner of the tile of zoom 0
+    pub origin_x: f64,
+    pub origin_y: f64,
+    /// Width (and height) of the tile of zoom 0 in meters
+    pub extent: f64,
+}
+
+impl TileMatrixSet {
+    pub fn web_mercator() -> Self {
+        Self {
+            id: "WebMercatorQuad".to_owned(),
+            crs: "EPSG:3857".to_owned(),
+            origin_x: -WEB_MERCATOR_EXTENT,
+            origin_y: WEB_MERCATOR_EXTENT,
+            extent: 2.0 * WEB_MERCATOR_EXTENT,
+        }
+    }
+
+    pub fn european_laea() -> Self {
+        Self {
+            id: "EuropeanETRS89_LAEAQuad".to_owned(),
+            crs: "EPSG:3035".to_owned(),
+            origin_x: 2_000_000.0,
+            origin_y: 5_500_000.0,
+            extent: 4_500_000.0,
+        }
+    }
+
+    pub fn is_web_mercator(&self) -> bool {
+        self.crs == "EPSG:3857"
+    }
+
+    /// Width (and height) of the tile in meters
+    pub fn tile_span(&self, zoom: u8) -> f64 {
+        self.extent / f64::from(1u32 << zoom)
+    }
+
+    pub fn bounds(&self, tile: &Tile) -> BBox {
+        let span = self.tile_span(tile.zoom);
+
+        let min_x = self.origin_x + f64::from(tile.x) * span;
+
+        let max_y = self.origin_y - f64::from(tile.y) * span;
+
+        BBox::new(min_x, max_y - span, min_x + span, max_y)
+    }
+
+    /// Tiles of the zoom level intersecting the bbox; tiles only touching its edge are excluded
+    pub fn covered_tiles(&self, bbox: &BBox, zoom: u8) -> impl Iterator<Item = Tile> {
+        let span = self.tile_span(zoom);
+
+        let max_index = f64::from((1u32 << zoom) - 1);
+
+        let first = |from_origin: f64| (from_origin / span).floor().clamp(0.0, max_index) as u32;
+
+        let last =
+            |from_origin: f64| ((from_origin / span).ceil() - 1.0).clamp(0.0, max_index) as u32;
+
+        let (min_x, max_x) = (
+            first(bbox.min_x - self.origin_x),
+            last(bbox.max_x - self.origin_x),
+        );
+
+        let (min_y, max_y) = (
+            first(self.origin_y - bbox.max_y),
+            last(self.origin_y - bbox.min_y),
+        );
+
+        (min_y..=max_y).flat_map(move |y| (min_x..=max_x).map(move |x| Tile { x, y, zoom }))
+    }
+
+    /// OGC TileMatrixSet JSON encoding of the zoom levels
+    pub fn to_json(&self, tile_size: u16, min_zoom: u8, max_zoom: u8) -> Value {
+        let crs = self.crs.strip_prefix("EPSG:").map_or_else(
+            || self.crs.clone(),
+            |code| format!("http://www.opengis.net/def/crs/EPSG/0/{code}"),
+        );
+
+        let tile_matrices: Vec<_> = (min_zoom..=max_zoom)
+            .map(|zoom| {
+                let cell_size = self.tile_span(zoom) / f64::from(tile_size);
+
+                json!({
+                    "id": zoom.to_string(),
+                    // standardized rendering pixel size of 0.28 mm
+                    "scaleDenominator": cell_size / 0.00028,
+                    "cellSize": cell_size,
+                    "cornerOfOrigin": "topLeft",
+                    "pointOfOrigin": [self.origin_x, self.origin_y],
+                    "tileWidth": tile_size,
+                    "tileHeight": tile_size,
+                    "matrixWidth": 1u32 << zoom,
+                    "matrixHeight": 1u32 << zoom,
+                })
+            })
+            .collect();
+
+        json!({
+            "id": self.id,
+            "crs": crs,
+            // point of origin is always easting, northing
+            "orderedAxes": ["E", "N"],
+            "tileMatrices": tile_matrices,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseTileMatrixSetError();
+
+impl Display for ParseTileMatrixSetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Expected WebMercatorQuad, EuropeanETRS89_LAEAQuad or <crs>,<origin_x>,<origin_y>,<extent>"
+        )
+    }
+}
+
+impl Error for ParseTileMatrixSetError {}
+
+impl FromStr for TileMatrixSet {
+    type Err = ParseTileMatrixSetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "WebMercatorQuad" => return Ok(Self::web_mercator()),
+            "EuropeanETRS89_LAEAQuad" => return Ok(Self::european_laea()),
+            _ => {}
+        }
+
+        let params: Vec<&str> = s.split(',').collect();
+
+        let [crs, origin_x, origin_y, extent] = params[..] else {
+            return Err(ParseTileMatrixSetError());
+        };
+
+        let parse = |value: &str| value.parse::<f64>().map_err(|_| ParseTileMatrixSetError());
+
+        Ok(Self {
+            id: "Custom".to_owned(),
+            crs: crs.to_owned(),
+            origin_x: parse(origin_x)?,
+            origin_y: parse(origin_y)?,
+            extent: parse(extent)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(zoom: u8, x: u32, y: u32) -> Tile {
+        Tile { x, y, zoom }
+    }
+
+    fn covered(tms: &TileMatrixSet, bbox: BBox, zoom: u8) -> Vec<(u32, u32)> {
+        tms.covered_tiles(&bbox, zoom)
+            .map(|tile| (tile.x, tile.y))
+            .collect()
+    }
+
+    fn custom() -> TileMatrixSet {
+        "EPSG:5514,-1000,1000,2000".parse().unwrap()
+    }
+
+    #[test]
+    fn parses_known_and_custom_sets() {
+        assert_eq!(
+            "WebMercatorQuad".parse::<TileMatrixSet>().unwrap(),
+            TileMatrixSet::web_mercator()
+        );
+
+        assert_eq!(
+            "EuropeanETRS89_LAEAQuad".parse::<TileMatrixSet>().unwrap(),
+            TileMatrixSet::european_laea()
+        );
+
+        assert_eq!(
+            custom(),
+            TileMatrixSet {
+                id: "Custom".to_owned(),
+                crs: "EPSG:5514".to_owned(),
+                origin_x: -1000.0,
+                origin_y: 1000.0,
+                extent: 2000.0,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_sets() {
+        for s in [
+            "",
+            "WebMercator",
+            "EPSG:5514,1,2",
+            "EPSG:5514,1,2,x",
+            "EPSG:5514,1,2,3,4",
+        ] {
+            assert!(s.parse::<TileMatrixSet>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn bounds_go_south_from_top_left_origin() {
+        let tms = custom();
+
+        assert_eq!(
+            tms.bounds(&tile(0, 0, 0)),
+            BBox::new(-1000.0, -1000.0, 1000.0, 1000.0)
+        );
+
+        assert_eq!(
+            tms.bounds(&tile(1, 1, 0)),
+            BBox::new(0.0, 0.0, 1000.0, 1000.0)
+        );
+
+        assert_eq!(
+            tms.bounds(&tile(2, 0, 3)),
+            BBox::new(-1000.0, -1000.0, -500.0, -500.0)
+        );
+    }
+
+    #[test]
+    fn web_mercator_tile_matches_extent() {
+        let bounds = TileMatrixSet::web_mercator().bounds(&tile(1, 0, 1));
+
+        assert_eq!(
+            bounds,
+            BBox::new(-WEB_MERCATOR_EXTENT, -WEB_MERCATOR_EXTENT, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn covers_tiles_intersecting_bbox() {
+        let tms = custom();
+
+        assert_eq!(
+            covered(&tms, BBox::new(-100.0, -100.0, 100.0, 100.0), 1),
+            [(0, 0), (1, 0), (0, 1), (1, 1)]
+        );
+
+        assert_eq!(
+            covered(&tms, BBox::new(100.0, 600.0, 200.0, 700.0), 2),
+            [(2, 0)]
+        );
+    }
+
+    #[test]
+    fn excludes_tiles_touching_bbox_edge() {
+        let tms = custom();
+
+        assert_eq!(
+            covered(&tms, BBox::new(0.0, 0.0, 1000.0, 1000.0), 1),
+            [(1, 0)]
+        );
+    }
+
+    #[test]
+    fn clamps_bbox_outside_of_set() {
+        let tms = custom();
+
+        assert_eq!(
+            covered(&tms, BBox::new(-5000.0, 500.0, -400.0, 5000.0), 2),
+            [(0, 0), (1, 0)]
+        );
+    }
+
+    #[test]
+    fn writes_ogc_tile_matrices() {
+        let json = TileMatrixSet::european_laea().to_json(256, 1, 2);
+
+        assert_eq!(json["id"], "EuropeanETRS89_LAEAQuad");
+
+        assert_eq!(json["crs"], "http://www.opengis.net/def/crs/EPSG/0/3035");
+
+        assert_eq!(json["orderedAxes"], json!(["E", "N"]));
+
+        let matrices = json["tileMatrices"].as_array().unwrap();
+
+        assert_eq!(matrices.len(), 2);
+
+        assert_eq!(matrices[0]["id"], "1");
+
+        assert_eq!(matrices[0]["cellSize"], 4_500_000.0 / 2.0 / 256.0);
+
+        assert_eq!(matrices[1]["matrixWidth"], 4);
+
+        assert_eq!(
+            matrices[1]["pointOfOrigin"],
+            json!([2_000_000.0, 5_500_000.0])
+        );
+    }
+
+    #[test]
+    fn keeps_non_epsg_crs() {
+        let mut tms = custom();
+
+        tms.crs = "ESRI:102067".to_owned();
+
+        assert_eq!(tms.to_json(256, 0, 0)["crs"], "ESRI:102067");
+    }
+}
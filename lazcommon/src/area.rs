@@ -0,0 +1,67 @@
+use geo::{Geometry, GeometryCollection, MultiPolygon};
+use geojson::GeoJson;
+use wkt::TryFromWkt;
+
+/// CRS of GeoJSON coordinates which are WGS84 longitude and latitude by RFC 7946
+pub const GEOJSON_CRS: &str = "EPSG:4326";
+
+/// Reads polygons from GeoJSON or WKT with CRS implied by the format; WKT has none.
+pub fn read_area(content: &str) -> (MultiPolygon, Option<&'static str>) {
+    let (geometries, crs) = match content.parse::<GeoJson>() {
+        Ok(geojson) => (
+            GeometryCollection::try_from(&geojson).expect("Invalid GeoJSON geometry"),
+            Some(GEOJSON_CRS),
+        ),
+        Err(_) => (
+            GeometryCollection::new_from(vec![
+                Geometry::try_from_wkt_str(content).expect("Area is neither GeoJSON nor WKT"),
+            ]),
+            None,
+        ),
+    };
+
+    let multi_polygon = MultiPolygon::new(
+        geometries
+            .into_iter()
+            .flat_map(|geometry| match geometry {
+                Geometry::Polygon(polygon) => vec![polygon],
+                Geometry::MultiPolygon(multi_polygon) => multi_polygon.0,
+                _ => panic!("Area must consist of polygons"),
+            })
+            .collect(),
+    );
+
+    (multi_polygon, crs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geojson_is_in_wgs84() {
+        let (area, crs) = read_area(
+            r#"{"type": "Polygon", "coordinates": [[[17, 48], [18, 48], [18, 49], [17, 48]]]}"#,
+        );
+
+        assert_eq!(area.0.len(), 1);
+
+        assert_eq!(crs, Some(GEOJSON_CRS));
+    }
+
+    #[test]
+    fn wkt_has_no_crs() {
+        let (area, crs) =
+            read_area("MULTIPOLYGON (((0 0, 1 0, 1 1, 0 0)), ((2 2, 3 2, 3 3, 2 2)))");
+
+        assert_eq!(area.0.len(), 2);
+
+        assert_eq!(crs, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Area must consist of polygons")]
+    fn rejects_lines() {
+        read_area("LINESTRING (0 0, 1 1)");
+    }
+}
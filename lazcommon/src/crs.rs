@@ -0,0 +1,116 @@
+use las::Header;
+
+const GEO_KEY_DIRECTORY_RECORD_ID: u16 = 34735;
+
+const WKT_RECORD_ID: u16 = 2112;
+
+const PROJECTED_CRS_GEO_KEY: u16 = 3072;
+
+const GEOGRAPHIC_CRS_GEO_KEY: u16 = 2048;
+
+/// Code of user-defined CRS which can't be referred by EPSG code
+const USER_DEFINED: u16 = 32767;
+
+/// Reads horizontal CRS of the file from its (E)VLRs as WKT or `EPSG:<code>`
+pub fn crs(header: &Header) -> Option<String> {
+    let vlrs = || header.vlrs().iter().chain(header.evlrs());
+
+    let wkt = vlrs()
+        .find(|vlr| vlr.user_id == "LASF_Projection" && vlr.record_id == WKT_RECORD_ID)
+        .map(|vlr| {
+            String::from_utf8_lossy(&vlr.data)
+                .trim_end_matches('\0')
+                .trim()
+                .to_owned()
+        })
+        .filter(|wkt| !wkt.is_empty());
+
+    if wkt.is_some() {
+        return wkt;
+    }
+
+    let geo_keys = &vlrs()
+        .find(|vlr| {
+            vlr.user_id == "LASF_Projection" && vlr.record_id == GEO_KEY_DIRECTORY_RECORD_ID
+        })?
+        .data;
+
+    geo_key(geo_keys, PROJECTED_CRS_GEO_KEY)
+        .or_else(|| geo_key(geo_keys, GEOGRAPHIC_CRS_GEO_KEY))
+        .map(|code| format!("EPSG:{code}"))
+}
+
+/// Reads short value of the key from GeoTIFF GeoKeyDirectoryTag
+fn geo_key(data: &[u8], key_id: u16) -> Option<u16> {
+    let shorts: Vec<_> = data
+        .chunks_exact(2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .collect();
+
+    let key_count = *shorts.get(3)? as usize;
+
+    shorts
+        .get(4..)?
+        .chunks_exact(4)
+        .take(key_count)
+        // location 0 means the value is stored in place of offset
+        .find(|key| key[0] == key_id && key[1] == 0)
+        .map(|key| key[3])
+        .filter(|code| *code != 0 && *code != USER_DEFINED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(keys: &[[u16; 4]]) -> Vec<u8> {
+        [1, 1, 0, keys.len() as u16]
+            .iter()
+            .chain(keys.iter().flatten())
+            .flat_map(|short| short.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn reads_projected_crs() {
+        let data = directory(&[[1024, 0, 1, 1], [3072, 0, 1, 5514]]);
+
+        assert_eq!(geo_key(&data, PROJECTED_CRS_GEO_KEY), Some(5514));
+
+        assert_eq!(geo_key(&data, GEOGRAPHIC_CRS_GEO_KEY), None);
+    }
+
+    #[test]
+    fn ignores_values_stored_in_other_tags() {
+        let data = directory(&[[3072, 34736, 1, 0]]);
+
+        assert_eq!(geo_key(&data, PROJECTED_CRS_GEO_KEY), None);
+    }
+
+    #[test]
+    fn ignores_user_defined_crs() {
+        let data = directory(&[[3072, 0, 1, USER_DEFINED]]);
+
+        assert_eq!(geo_key(&data, PROJECTED_CRS_GEO_KEY), None);
+    }
+
+    #[test]
+    fn reads_only_declared_keys() {
+        let mut data = directory(&[[1024, 0, 1, 1]]);
+
+        data.extend(
+            [3072u16, 0, 1, 5514]
+                .iter()
+                .flat_map(|short| short.to_le_bytes()),
+        );
+
+        assert_eq!(geo_key(&data, PROJECTED_CRS_GEO_KEY), None);
+    }
+
+    #[test]
+    fn handles_truncated_directory() {
+        assert_eq!(geo_key(&[1, 0, 1], PROJECTED_CRS_GEO_KEY), None);
+
+        assert_eq!(geo_key(&directory(&[])[..6], PROJECTED_CRS_GEO_KEY), None);
+    }
+}
@@ -0,0 +1,59 @@
+use glob::glob;
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+/// Lists point cloud files of the input which is a directory, a glob pattern, a point cloud file
+/// or a text file with one path per line. Unreadable directory entries are logged and skipped.
+pub fn input_files(input: &Path) -> Vec<PathBuf> {
+    if input.is_dir() {
+        return WalkDir::new(input)
+            .into_iter()
+            .filter_map(|entry| {
+                entry
+                    .map_err(|err| {
+                        eprintln!(
+                            "ERROR {}: {err}",
+                            err.path().unwrap_or(input).to_string_lossy()
+                        );
+                    })
+                    .ok()
+            })
+            .map(|entry| entry.into_path())
+            .filter(|path| is_point_cloud(path))
+            .collect();
+    }
+
+    if input.is_file() {
+        if is_point_cloud(input) {
+            return vec![input.to_path_buf()];
+        }
+
+        return read_to_string(input)
+            .unwrap()
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .collect();
+    }
+
+    glob(input.to_str().expect("Input is not valid UTF-8"))
+        .expect("Input is neither a file, a directory nor a valid glob pattern")
+        .filter_map(|path| {
+            path.map_err(|err| {
+                eprintln!("ERROR {}: {}", err.path().to_string_lossy(), err.error());
+            })
+            .ok()
+        })
+        .filter(|path| is_point_cloud(path))
+        .collect()
+}
+
+/// Is `*.las`, `*.laz` or `*.copc.laz`, case-insensitive
+pub fn is_point_cloud(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("las") || ext.eq_ignore_ascii_case("laz"))
+}
@@ -0,0 +1,7 @@
+//! Helpers shared by `laztile`, `lazindex` and `laz2dem`
+
+#[cfg(feature = "area")]
+pub mod area;
+pub mod crs;
+pub mod input;
+pub mod stop;
@@ -0,0 +1,26 @@
+use std::{
+    process::exit,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Set by SIGINT or SIGTERM
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Makes SIGINT and SIGTERM request a graceful stop announced by the message.
+///
+/// Second signal terminates without waiting.
+pub fn install_stop_handler(message: &'static str) {
+    ctrlc::set_handler(move || {
+        if STOP.swap(true, Ordering::Relaxed) {
+            exit(1);
+        }
+
+        println!("{message}");
+    })
+    .expect("Failed to set signal handler");
+}
+
+/// Graceful stop was requested; running work is finished and the rest skipped
+pub fn stopped() -> bool {
+    STOP.load(Ordering::Relaxed)
+}
@@ -0,0 +1,255 @@
+use crate::table_exists;
+use geo::{
+    BooleanOps, BoundingRect, Coord, Intersects, MapCoords, MultiPolygon, Polygon, Rect,
+    unary_union,
+};
+use geojson::{Feature, FeatureCollection, GeoJson, JsonObject};
+use lazcommon::area::read_area;
+use proj::Proj;
+use rusqlite::{Connection, OpenFlags};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs::{read_to_string, write},
+    path::Path,
+};
+
+/// Exports footprints (or extents if footprints were not indexed) of all files as GeoJSON in EPSG:4326.
+pub fn export(database: &Path, output: &Path, source_projection: Option<&str>) {
+    let to_4326 = Proj::new_known_crs("EPSG:3857", "EPSG:4326", None)
+        .expect("Failed to create PROJ transformation");
+
+    let features = footprints(database, source_projection)
+        .into_iter()
+        .map(|(file, footprint)| {
+            let mut properties = JsonObject::new();
+
+            properties.insert("file".to_owned(), Value::String(file));
+
+            Feature {
+                bbox: None,
+                geometry: Some((&transform(&footprint, &to_4326)).into()),
+                id: None,
+                properties: Some(properties),
+                foreign_members: None,
+            }
+        })
+        .collect();
+
+    write_geojson(output, features);
+}
+
+/// Prints (and optionally exports as GeoJSON in EPSG:4326) parts of the bbox or area not covered by any file.
+pub fn gaps(
+    database: &Path,
+    bbox: Option<&[f64]>,
+    area: Option<&Path>,
+    crs: Option<&str>,
+    source_projection: Option<&str>,
+    output: Option<&Path>,
+) {
+    let (mut area, format_crs) = match area {
+        Some(area) => read_area(&read_to_string(area).unwrap()),
+        None => {
+            let [min_x, min_y, max_x, max_y] = bbox
+                .expect("bbox or area is required")
+                .try_into()
+                .expect("bbox must have 4 numbers");
+
+            let rect = MultiPolygon::new(vec![
+                Rect::new(Coord { x: min_x, y: min_y }, Coord { x: max_x, y: max_y }).to_polygon(),
+            ]);
+
+            (rect, None)
+        }
+    };
+
+    let proj = crs.or(format_crs).map(|crs| {
+        Proj::new_known_crs(crs, "EPSG:3857", None).expect("Failed to create PROJ transformation")
+    });
+
+    if let Some(ref proj) = proj {
+        area = transform(&area, proj);
+    }
+
+    let area_rect = area.bounding_rect().expect("Area is empty");
+
+    let footprints: Vec<_> = footprints(database, source_projection)
+        .into_iter()
+        .map(|(_, footprint)| footprint)
+        .filter(|footprint| footprint.intersects(&area_rect))
+        .collect();
+
+    let gaps = area.difference(&unary_union(&footprints));
+
+    let to_4326 = Proj::new_known_crs("EPSG:3857", "EPSG:4326", None)
+        .expect("Failed to create PROJ transformation");
+
+    let gaps = transform(&gaps, &to_4326);
+
+    for gap in &gaps {
+        let rect = gap.bounding_rect().unwrap();
+
+        println!(
+            "GAP {},{},{},{}",
+            rect.min().x,
+            rect.min().y,
+            rect.max().x,
+            rect.max().y
+        );
+    }
+
+    println!("Found {} gaps", gaps.0.len());
+
+    if let Some(output) = output {
+        let features = gaps
+            .into_iter()
+            .map(|gap| Feature {
+                bbox: None,
+                geometry: Some((&gap).into()),
+                id: None,
+                properties: None,
+                foreign_members: None,
+            })
+            .collect();
+
+        write_geojson(output, features);
+    }
+}
+
+fn write_geojson(output: &Path, features: Vec<Feature>) {
+    let collection = GeoJson::FeatureCollection(FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    });
+
+    write(output, collection.to_string()).unwrap();
+}
+
+fn transform(multi_polygon: &MultiPolygon, proj: &Proj) -> MultiPolygon {
+    multi_polygon.map_coords(|coord| {
+        let (x, y) = proj.convert((coord.x, coord.y)).unwrap();
+
+        Coord { x, y }
+    })
+}
+
+/// Reads footprints of all indexed files as polygons in EPSG:3857
+fn footprints(database: &Path, source_projection: Option<&str>) -> Vec<(String, MultiPolygon)> {
+    let conn = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+
+    let sql = if table_exists(&conn, "laz_footprint") {
+        "SELECT laz_index.file, laz_index.crs, laz_index.min_x, laz_index.max_x, laz_index.min_y, laz_index.max_y,
+            footprint.cell_size, footprint.min_x, footprint.min_y, footprint.columns, footprint.rows, footprint.cells
+        FROM laz_index LEFT JOIN laz_footprint AS footprint USING (file)"
+    } else {
+        "SELECT file, crs, min_x, max_x, min_y, max_y, NULL, NULL, NULL, NULL, NULL, NULL FROM laz_index"
+    };
+
+    let mut stmt = conn.prepare(sql).unwrap();
+
+    let mut projs = HashMap::new();
+
+    stmt.query_map((), |row| {
+        let file: String = row.get(0)?;
+
+        let crs: Option<String> = row.get(1)?;
+
+        let polygons = match row.get::<_, Option<f64>>(6)? {
+            Some(cell_size) => occupied_cells(
+                cell_size,
+                row.get(7)?,
+                row.get(8)?,
+                row.get(9)?,
+                row.get(10)?,
+                &row.get::<_, Vec<u8>>(11)?,
+            ),
+            None => vec![
+                Rect::new(
+                    Coord {
+                        x: row.get(2)?,
+                        y: row.get(4)?,
+                    },
+                    Coord {
+                        x: row.get(3)?,
+                        y: row.get(5)?,
+                    },
+                )
+                .to_polygon(),
+            ],
+        };
+
+        Ok((file, crs, polygons))
+    })
+    .unwrap()
+    .map(|row| {
+        let (file, crs, polygons) = row.unwrap();
+
+        let footprint = unary_union(&polygons);
+
+        let footprint = match crs.as_deref().or(source_projection) {
+            Some(crs) => transform(
+                &footprint,
+                projs.entry(crs.to_owned()).or_insert_with(|| {
+                    Proj::new_known_crs(crs, "EPSG:3857", None)
+                        .expect("Failed to create PROJ transformation")
+                }),
+            ),
+            None => footprint,
+        };
+
+        (file, footprint)
+    })
+    .collect()
+}
+
+/// Converts occupancy grid to rectangles of consecutive occupied cells of every row
+fn occupied_cells(
+    cell_size: f64,
+    min_x: f64,
+    min_y: f64,
+    columns: u32,
+    rows: u32,
+    cells: &[u8],
+) -> Vec<Polygon> {
+    let is_occupied = |i: usize| cells[i >> 3] & (1 << (i & 7)) != 0;
+
+    let columns = columns as usize;
+
+    let mut polygons = Vec::new();
+
+    for row in 0..rows as usize {
+        let row_start = row * columns;
+
+        let mut run_start = None;
+
+        for column in 0..=columns {
+            let occupied = column < columns && is_occupied(row_start + column);
+
+            match (occupied, run_start) {
+                (true, None) => run_start = Some(column),
+                (false, Some(start)) => {
+                    run_start = None;
+
+                    polygons.push(
+                        Rect::new(
+                            Coord {
+                                x: min_x + start as f64 * cell_size,
+                                y: min_y + row as f64 * cell_size,
+                            },
+                            Coord {
+                                x: min_x + column as f64 * cell_size,
+                                y: min_y + (row + 1) as f64 * cell_size,
+                            },
+                        )
+                        .to_polygon(),
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    polygons
+}
@@ -0,0 +1,105 @@
+use las::Bounds;
+
+/// Coarse occupancy grid of file points; a cell is occupied if it contains any point.
+pub struct Footprint {
+    pub cell_size: f64,
+    pub min_x: f64,
+    pub min_y: f64,
+    pub columns: u32,
+    pub rows: u32,
+    /// Bit per cell, row by row from south-west
+    pub cells: Vec<u8>,
+}
+
+impl Footprint {
+    pub fn new(bounds: &Bounds, cell_size: f64) -> Self {
+        let columns = ((bounds.max.x - bounds.min.x) / cell_size).floor() as u32 + 1;
+
+        let rows = ((bounds.max.y - bounds.min.y) / cell_size).floor() as u32 + 1;
+
+        Self {
+            cell_size,
+            min_x: bounds.min.x,
+            min_y: bounds.min.y,
+            columns,
+            rows,
+            cells: vec![0; (columns as usize * rows as usize).div_ceil(8)],
+        }
+    }
+
+    pub fn add(&mut self, x: f64, y: f64) {
+        let column =
+            (((x - self.min_x) / self.cell_size).floor().max(0.0) as u32).min(self.columns - 1);
+
+        let row = (((y - self.min_y) / self.cell_size).floor().max(0.0) as u32).min(self.rows - 1);
+
+        let i = row as usize * self.columns as usize + column as usize;
+
+        self.cells[i >> 3] |= 1 << (i & 7);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use las::Vector;
+
+    fn bounds(max_x: f64, max_y: f64) -> Bounds {
+        Bounds {
+            min: Vector {
+                x: 100.0,
+                y: 200.0,
+                z: 0.0,
+            },
+            max: Vector {
+                x: max_x,
+                y: max_y,
+                z: 0.0,
+            },
+        }
+    }
+
+    fn occupied(footprint: &Footprint) -> Vec<usize> {
+        (0..(footprint.columns * footprint.rows) as usize)
+            .filter(|i| footprint.cells[i >> 3] & (1 << (i & 7)) != 0)
+            .collect()
+    }
+
+    #[test]
+    fn grid_covers_bounds() {
+        let footprint = Footprint::new(&bounds(135.0, 210.0), 10.0);
+
+        assert_eq!((footprint.columns, footprint.rows), (4, 2));
+
+        assert_eq!(footprint.cells.len(), 1);
+
+        // max edge gets its own cell
+        let footprint = Footprint::new(&bounds(140.0, 200.0), 10.0);
+
+        assert_eq!((footprint.columns, footprint.rows), (5, 1));
+    }
+
+    #[test]
+    fn cells_are_indexed_by_rows_from_south_west() {
+        let mut footprint = Footprint::new(&bounds(135.0, 225.0), 10.0);
+
+        footprint.add(100.0, 200.0);
+
+        footprint.add(125.0, 215.0);
+
+        footprint.add(135.0, 225.0);
+
+        assert_eq!(occupied(&footprint), [0, 4 + 2, 2 * 4 + 3]);
+    }
+
+    #[test]
+    fn points_outside_bounds_are_clamped() {
+        let mut footprint = Footprint::new(&bounds(135.0, 210.0), 10.0);
+
+        footprint.add(99.0, 199.0);
+
+        footprint.add(500.0, 500.0);
+
+        assert_eq!(occupied(&footprint), [0, 4 + 3]);
+    }
+}
@@ -1,66 +1,662 @@
-use clap::Parser;
-use las::Reader;
-use rusqlite::Connection;
-use std::path::PathBuf;
-use walkdir::WalkDir;
+mod coverage;
+mod footprint;
+
+use clap::{Parser, Subcommand};
+use footprint::Footprint;
+use las::{Bounds, Header, Reader};
+use lazcommon::{
+    crs::crs,
+    input::input_files,
+    stop::{install_stop_handler, stopped},
+};
+use proj::Proj;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use rusqlite::{Connection, params};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fs::{Metadata, metadata},
+    path::{Path, PathBuf},
+    sync::mpsc::channel,
+    thread,
+    time::UNIX_EPOCH,
+};
 
 #[derive(Parser, Debug, PartialEq)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Options {
-    /// Directory with *.laz files
-    directory: PathBuf, // "/home/martin/18TB"
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Directory with *.las / *.laz files, glob pattern (quoted) or text file listing the files one per line
+    #[clap(required = true)]
+    input: Option<PathBuf>, // "/home/martin/18TB"
 
     /// Output database file
-    database: PathBuf, // "/home/martin/14TB/sk-new-dmr/laztiles.sqlite"
+    #[clap(required = true)]
+    database: Option<PathBuf>, // "/home/martin/14TB/sk-new-dmr/laztiles.sqlite"
+
+    /// Index also bounding boxes of LAZ chunks so that only chunks intersecting the rendered area are read.
+    /// All points must be decompressed. COPC files are not chunk-indexed as they have their own hierarchy.
+    #[clap(long)]
+    chunks: bool,
+
+    /// Count points of every class so that files without ground points are skipped. All points must be decompressed.
+    #[clap(long)]
+    class_counts: bool,
+
+    /// Store occupancy grid of points with cells of this size (in units of the file projection)
+    /// so that only files really covering the rendered area are read. All points must be decompressed.
+    #[clap(long, value_parser = parse_cell_size)]
+    footprint_cell_size: Option<f64>,
+
+    /// Update existing database: index new and changed (by size or modification time) files and remove deleted ones
+    #[clap(long)]
+    update: bool,
+
+    /// Projection of files without one in their header
+    #[clap(long)]
+    source_projection: Option<String>,
+
+    /// Store file extents also in EPSG:3857 so that `laz2dem` needs no `--source-projection`
+    /// and files of different projections can be mixed in one index
+    #[clap(long)]
+    mercator: bool,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum Command {
+    /// Export footprints (or extents if footprints were not indexed) of files as GeoJSON in EPSG:4326
+    Coverage {
+        /// LAZ index database file
+        database: PathBuf,
+
+        /// Output GeoJSON file
+        output: PathBuf,
+
+        /// Projection of files without one in the index; default is EPSG:3857
+        #[clap(long)]
+        source_projection: Option<String>,
+    },
+
+    /// Print parts of the bbox or area which are not covered by any file
+    Gaps {
+        /// LAZ index database file
+        database: PathBuf,
+
+        /// Bounding box to check as min_x,min_y,max_x,max_y
+        #[clap(long, value_delimiter = ',', required_unless_present = "area")]
+        bbox: Option<Vec<f64>>,
+
+        /// GeoJSON or WKT file with polygons of the area to check
+        #[clap(long, conflicts_with = "bbox")]
+        area: Option<PathBuf>,
+
+        /// Projection of `--bbox` and `--area`; default is EPSG:4326 for GeoJSON area and EPSG:3857 otherwise
+        #[clap(long)]
+        crs: Option<String>,
+
+        /// Projection of files without one in the index; default is EPSG:3857
+        #[clap(long)]
+        source_projection: Option<String>,
+
+        /// Write gaps to this GeoJSON file in EPSG:4326
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// Number of points per chunk if the file uses variable-sized chunks
+const DEFAULT_CHUNK_SIZE: u64 = 50_000;
+
+/// Points which can be read without decompressing preceding points
+struct Chunk {
+    first_point: u64,
+    point_count: u64,
+    /// min_x, max_x, min_y, max_y
+    bounds: [f64; 4],
+}
+
+/// Indexed properties of a point cloud file
+struct FileIndex {
+    file: String,
+    bounds: Bounds,
+    point_count: u64,
+    point_format: u8,
+    version: String,
+    crs: Option<String>,
+    /// min_x, max_x, min_y, max_y in EPSG:3857
+    mercator_bounds: Option<[f64; 4]>,
+    size: u64,
+    mtime: u64,
+    chunks: Vec<Chunk>,
+    class_counts: BTreeMap<u8, u64>,
+    footprint: Option<Footprint>,
 }
 
 fn main() {
     let options = Options::parse();
 
-    let conn = Connection::open(options.database).unwrap();
+    // files being indexed are finished and stored, the rest is skipped
+    install_stop_handler("Stopping after the current files");
+
+    match options.command {
+        Some(Command::Coverage {
+            ref database,
+            ref output,
+            ref source_projection,
+        }) => coverage::export(database, output, source_projection.as_deref()),
+        Some(Command::Gaps {
+            ref database,
+            ref bbox,
+            ref area,
+            ref crs,
+            ref source_projection,
+            ref output,
+        }) => coverage::gaps(
+            database,
+            bbox.as_deref(),
+            area.as_deref(),
+            crs.as_deref(),
+            source_projection.as_deref(),
+            output.as_deref(),
+        ),
+        None => index(&options),
+    }
+}
+
+fn index(options: &Options) {
+    let database = options.database.as_ref().unwrap();
+
+    if options.update && !database.exists() {
+        panic!("Database file doesn't exist");
+    }
+
+    if !options.update && database.exists() {
+        panic!("Database file already exists; use --update to update it");
+    }
+
+    let mut conn = Connection::open(database).unwrap();
+
+    let has_rtree = table_exists(&conn, "laz_rtree");
+
+    let mercator = options.mercator || table_exists(&conn, "laz_rtree_3857");
+
+    if mercator && options.update && !table_exists(&conn, "laz_rtree_3857") {
+        panic!("Existing index has no EPSG:3857 extents; create a new one with --mercator");
+    }
 
     conn.execute(
-      "CREATE TABLE laz_index (min_x NUMBER, max_x NUMBER, min_y NUMBER, max_y NUMBER, file VARCHAR)", ()
-  )
-  .unwrap();
+        "CREATE TABLE IF NOT EXISTS laz_index (min_x NUMBER, max_x NUMBER, min_y NUMBER, max_y NUMBER, file VARCHAR, point_count INTEGER, min_z NUMBER, max_z NUMBER, point_format INTEGER, version VARCHAR, crs VARCHAR, size INTEGER, mtime INTEGER, mercator_min_x NUMBER, mercator_max_x NUMBER, mercator_min_y NUMBER, mercator_max_y NUMBER, id INTEGER)",
+        (),
+    )
+    .unwrap();
+
+    // `laz2dem` uses optional tables if they exist so these are created only when requested
+    for (requested, query) in [
+        (
+            options.chunks,
+            "CREATE TABLE IF NOT EXISTS laz_chunk (file VARCHAR, first_point INTEGER, point_count INTEGER, min_x NUMBER, max_x NUMBER, min_y NUMBER, max_y NUMBER)",
+        ),
+        (
+            options.class_counts,
+            "CREATE TABLE IF NOT EXISTS laz_class_count (file VARCHAR, class INTEGER, point_count INTEGER, PRIMARY KEY (file, class))",
+        ),
+        (
+            options.footprint_cell_size.is_some(),
+            "CREATE TABLE IF NOT EXISTS laz_footprint (file VARCHAR PRIMARY KEY, cell_size NUMBER, min_x NUMBER, min_y NUMBER, columns INTEGER, rows INTEGER, cells BLOB)",
+        ),
+    ] {
+        if requested {
+            conn.execute(query, ()).unwrap();
+        }
+    }
+
+    migrate(&conn);
+
+    // R*Tree of file extents keyed by `laz_index.id`
+    if !has_rtree {
+        conn.execute(
+            "CREATE VIRTUAL TABLE laz_rtree USING rtree(id, min_x, max_x, min_y, max_y)",
+            (),
+        )
+        .unwrap();
 
-    let mut stmt = conn
-        .prepare("INSERT INTO laz_index VALUES (?1, ?2, ?3, ?4, ?5)")
+        conn.execute(
+            "INSERT INTO laz_rtree SELECT id, min_x, max_x, min_y, max_y FROM laz_index",
+            (),
+        )
         .unwrap();
+    }
+
+    if mercator {
+        conn.execute(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS laz_rtree_3857 USING rtree(id, min_x, max_x, min_y, max_y)",
+            (),
+        )
+        .unwrap();
+    }
+
+    // size and mtime of already indexed files
+    let indexed: HashMap<String, (Option<u64>, Option<u64>)> = conn
+        .prepare("SELECT file, size, mtime FROM laz_index")
+        .unwrap()
+        .query_map((), |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect();
+
+    for file in indexed.keys() {
+        if !Path::new(file).exists() {
+            println!("REMOVE {file}");
+
+            delete(&mut conn, file, mercator);
+        }
+    }
+
+    let paths: Vec<_> = input_files(options.input.as_ref().unwrap())
+        .into_iter()
+        .filter(|path| {
+            let Some(&(size, mtime)) = indexed.get(path.to_string_lossy().as_ref()) else {
+                return true;
+            };
+
+            // unreadable metadata is reported when indexing
+            metadata(path).map_or(true, |metadata| {
+                size != Some(metadata.len()) || mtime != mtime_of(&metadata).ok()
+            })
+        })
+        .collect();
+
+    println!("Indexing {} files", paths.len());
+
+    let (sender, receiver) = channel();
+
+    thread::scope(|scope| {
+        let options = &options;
 
-    for dir in WalkDir::new(options.directory) {
-        let dir = dir.unwrap();
-
-        println!("{}", dir.file_name().to_string_lossy());
-
-        if dir
-            .path()
-            .extension()
-            .map(|ext| ext == "laz")
-            .unwrap_or(false)
-        {
-            let reader = Reader::from_path(dir.path()).unwrap();
-
-            let bounds = reader.header().bounds();
-
-            let _ = stmt
-                .execute((
-                    bounds.min.x,
-                    bounds.max.x,
-                    bounds.min.y,
-                    bounds.max.y,
-                    dir.path().to_string_lossy(),
-                ))
-                .unwrap();
+        let paths = &paths;
+
+        // headers are read in parallel, the database is written from this thread only
+        scope.spawn(move || {
+            paths.par_iter().for_each_with(sender, |sender, path| {
+                if stopped() {
+                    return;
+                }
+
+                sender
+                    .send((path, index_file(path, options, mercator)))
+                    .unwrap();
+            });
+        });
+
+        for (path, file_index) in receiver {
+            match file_index {
+                Ok(file_index) => {
+                    println!("{}", path.to_string_lossy());
+
+                    delete(&mut conn, &file_index.file, mercator);
+
+                    insert(&mut conn, &file_index);
+                }
+                Err(err) => {
+                    eprintln!("ERROR {}: {err}", path.to_string_lossy());
+                }
+            }
         }
+    });
+
+    if stopped() {
+        println!("Stopped; run again with --update to index the remaining files");
+    }
+
+    if table_exists(&conn, "laz_chunk") {
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS laz_chunk_file_index ON laz_chunk (file)",
+            (),
+        )
+        .unwrap();
     }
 
     for query in [
-        "CREATE UNIQUE INDEX laz_file_unique ON laz_index (file)",
-        "CREATE INDEX laz_min_x_index ON laz_index (min_x)",
-        "CREATE INDEX laz_max_x_index ON laz_index (max_x)",
-        "CREATE INDEX laz_min_y_index ON laz_index (min_y)",
-        "CREATE INDEX laz_max_y_index ON laz_index (max_y)",
+        "CREATE UNIQUE INDEX IF NOT EXISTS laz_file_unique ON laz_index (file)",
+        "CREATE INDEX IF NOT EXISTS laz_min_x_index ON laz_index (min_x)",
+        "CREATE INDEX IF NOT EXISTS laz_max_x_index ON laz_index (max_x)",
+        "CREATE INDEX IF NOT EXISTS laz_min_y_index ON laz_index (min_y)",
+        "CREATE INDEX IF NOT EXISTS laz_max_y_index ON laz_index (max_y)",
     ] {
         conn.execute(query, ()).unwrap();
     }
 }
+
+fn table_exists(conn: &Connection, name: &str) -> bool {
+    conn.prepare_cached("SELECT 1 FROM sqlite_master WHERE name = ?1")
+        .unwrap()
+        .exists([name])
+        .unwrap()
+}
+
+fn parse_cell_size(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(cell_size) if cell_size > 0.0 && cell_size.is_finite() => Ok(cell_size),
+        Ok(_) => Err("cell size must be positive".to_owned()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Adds columns missing in databases created by older versions
+fn migrate(conn: &Connection) {
+    for (column, definition) in [
+        ("point_count", "INTEGER"),
+        ("min_z", "NUMBER"),
+        ("max_z", "NUMBER"),
+        ("point_format", "INTEGER"),
+        ("version", "VARCHAR"),
+        ("crs", "VARCHAR"),
+        ("size", "INTEGER"),
+        ("mtime", "INTEGER"),
+        ("mercator_min_x", "NUMBER"),
+        ("mercator_max_x", "NUMBER"),
+        ("mercator_min_y", "NUMBER"),
+        ("mercator_max_y", "NUMBER"),
+        ("id", "INTEGER"),
+    ] {
+        let exists: bool = conn
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('laz_index') WHERE name = ?1",
+                [column],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        if !exists {
+            conn.execute(
+                &format!("ALTER TABLE laz_index ADD COLUMN {column} {definition}"),
+                (),
+            )
+            .unwrap();
+        }
+    }
+
+    // implicit `rowid` may change by VACUUM; older R*Trees referred to it
+    conn.execute("UPDATE laz_index SET id = rowid WHERE id IS NULL", ())
+        .unwrap();
+
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS laz_id_unique ON laz_index (id)",
+        (),
+    )
+    .unwrap();
+}
+
+fn mtime_of(metadata: &Metadata) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    Ok(metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn index_file(
+    path: &Path,
+    options: &Options,
+    mercator: bool,
+) -> Result<FileIndex, Box<dyn Error + Send + Sync>> {
+    let metadata = metadata(path)?;
+
+    let mut reader = Reader::from_path(path)?;
+
+    let header = reader.header();
+
+    let version = header.version();
+
+    let bounds = header.bounds();
+
+    let crs = crs(header).or_else(|| options.source_projection.clone());
+
+    let mercator_bounds = if mercator {
+        let [min_x, min_y, max_x, max_y] = match crs {
+            Some(ref crs) => Proj::new_known_crs(crs, "EPSG:3857", None)?.transform_bounds(
+                bounds.min.x,
+                bounds.min.y,
+                bounds.max.x,
+                bounds.max.y,
+                11,
+            )?,
+            None => [bounds.min.x, bounds.min.y, bounds.max.x, bounds.max.y],
+        };
+
+        Some([min_x, max_x, min_y, max_y])
+    } else {
+        None
+    };
+
+    let mut file_index = FileIndex {
+        file: path.to_string_lossy().into_owned(),
+        bounds,
+        point_count: header.number_of_points(),
+        point_format: header.point_format().to_u8()?,
+        version: format!("{}.{}", version.major, version.minor),
+        crs,
+        mercator_bounds,
+        size: metadata.len(),
+        mtime: mtime_of(&metadata)?,
+        chunks: Vec::new(),
+        class_counts: BTreeMap::new(),
+        footprint: options
+            .footprint_cell_size
+            .map(|cell_size| Footprint::new(&bounds, cell_size)),
+    };
+
+    let chunk_size = (options.chunks && !is_copc(header)).then(|| chunk_size(header));
+
+    if chunk_size.is_some() || options.class_counts || file_index.footprint.is_some() {
+        scan(&mut reader, chunk_size, &mut file_index)?;
+    }
+
+    if !options.class_counts {
+        file_index.class_counts.clear();
+    }
+
+    Ok(file_index)
+}
+
+fn delete(conn: &mut Connection, file: &str, mercator: bool) {
+    let tx = conn.transaction().unwrap();
+
+    for rtree in if mercator {
+        &["laz_rtree", "laz_rtree_3857"][..]
+    } else {
+        &["laz_rtree"]
+    } {
+        tx.execute(
+            &format!("DELETE FROM {rtree} WHERE id IN (SELECT id FROM laz_index WHERE file = ?1)"),
+            [file],
+        )
+        .unwrap();
+    }
+
+    for table in ["laz_index", "laz_chunk", "laz_class_count", "laz_footprint"] {
+        if !table_exists(&tx, table) {
+            continue;
+        }
+
+        tx.execute(&format!("DELETE FROM {table} WHERE file = ?1"), [file])
+            .unwrap();
+    }
+
+    tx.commit().unwrap();
+}
+
+fn insert(conn: &mut Connection, file_index: &FileIndex) {
+    let tx = conn.transaction().unwrap();
+
+    let bounds = file_index.bounds;
+
+    let mercator_bounds = file_index.mercator_bounds;
+
+    // stable id referred by R*Trees
+    let id: i64 = tx
+        .query_row("SELECT IFNULL(MAX(id), 0) + 1 FROM laz_index", (), |row| {
+            row.get(0)
+        })
+        .unwrap();
+
+    tx.execute(
+        "INSERT INTO laz_index VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
+        params![
+            bounds.min.x,
+            bounds.max.x,
+            bounds.min.y,
+            bounds.max.y,
+            &file_index.file,
+            file_index.point_count,
+            bounds.min.z,
+            bounds.max.z,
+            file_index.point_format,
+            &file_index.version,
+            &file_index.crs,
+            file_index.size,
+            file_index.mtime,
+            mercator_bounds.map(|bounds| bounds[0]),
+            mercator_bounds.map(|bounds| bounds[1]),
+            mercator_bounds.map(|bounds| bounds[2]),
+            mercator_bounds.map(|bounds| bounds[3]),
+            id,
+        ],
+    )
+    .unwrap();
+
+    tx.execute(
+        "INSERT INTO laz_rtree VALUES (?1, ?2, ?3, ?4, ?5)",
+        (id, bounds.min.x, bounds.max.x, bounds.min.y, bounds.max.y),
+    )
+    .unwrap();
+
+    if let Some(mercator_bounds) = mercator_bounds {
+        tx.execute(
+            "INSERT INTO laz_rtree_3857 VALUES (?1, ?2, ?3, ?4, ?5)",
+            (
+                id,
+                mercator_bounds[0],
+                mercator_bounds[1],
+                mercator_bounds[2],
+                mercator_bounds[3],
+            ),
+        )
+        .unwrap();
+    }
+
+    for chunk in &file_index.chunks {
+        tx.execute(
+            "INSERT INTO laz_chunk VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            (
+                &file_index.file,
+                chunk.first_point,
+                chunk.point_count,
+                chunk.bounds[0],
+                chunk.bounds[1],
+                chunk.bounds[2],
+                chunk.bounds[3],
+            ),
+        )
+        .unwrap();
+    }
+
+    if let Some(ref footprint) = file_index.footprint {
+        tx.execute(
+            "INSERT INTO laz_footprint VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            (
+                &file_index.file,
+                footprint.cell_size,
+                footprint.min_x,
+                footprint.min_y,
+                footprint.columns,
+                footprint.rows,
+                &footprint.cells,
+            ),
+        )
+        .unwrap();
+    }
+
+    for (class, point_count) in &file_index.class_counts {
+        tx.execute(
+            "INSERT INTO laz_class_count VALUES (?1, ?2, ?3)",
+            (&file_index.file, class, point_count),
+        )
+        .unwrap();
+    }
+
+    tx.commit().unwrap();
+}
+
+fn is_copc(header: &Header) -> bool {
+    header
+        .vlrs()
+        .iter()
+        .any(|vlr| vlr.user_id == "copc" && vlr.record_id == 1)
+}
+
+/// Chunk size from LASzip VLR; variable-sized chunks are indexed as if they were fixed-sized.
+fn chunk_size(header: &Header) -> u64 {
+    header
+        .vlrs()
+        .iter()
+        .find(|vlr| vlr.user_id == "laszip encoded" && vlr.record_id == 22204)
+        .map(|vlr| u32::from_le_bytes(vlr.data[12..16].try_into().unwrap()))
+        .filter(|chunk_size| *chunk_size != u32::MAX)
+        .map_or(DEFAULT_CHUNK_SIZE, u64::from)
+}
+
+/// Reads all points to compute chunk bounds (if `chunk_size` is given), class counts and footprint
+fn scan(
+    reader: &mut Reader,
+    chunk_size: Option<u64>,
+    file_index: &mut FileIndex,
+) -> Result<(), las::Error> {
+    let mut chunk = Chunk {
+        first_point: 0,
+        point_count: 0,
+        bounds: [f64::MAX, f64::MIN, f64::MAX, f64::MIN],
+    };
+
+    for point in reader.points() {
+        let point = point?;
+
+        *file_index
+            .class_counts
+            .entry(u8::from(point.classification))
+            .or_default() += 1;
+
+        if let Some(ref mut footprint) = file_index.footprint {
+            footprint.add(point.x, point.y);
+        }
+
+        let Some(chunk_size) = chunk_size else {
+            continue;
+        };
+
+        chunk.bounds = [
+            chunk.bounds[0].min(point.x),
+            chunk.bounds[1].max(point.x),
+            chunk.bounds[2].min(point.y),
+            chunk.bounds[3].max(point.y),
+        ];
+
+        chunk.point_count += 1;
+
+        if chunk.point_count == chunk_size {
+            let first_point = chunk.first_point + chunk.point_count;
+
+            file_index.chunks.push(chunk);
+
+            chunk = Chunk {
+                first_point,
+                point_count: 0,
+                bounds: [f64::MAX, f64::MIN, f64::MAX, f64::MIN],
+            };
+        }
+    }
+
+    if chunk.point_count > 0 {
+        file_index.chunks.push(chunk);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use las::{Reader, Writer};
+use lazcommon::stop::stopped;
+use rusqlite::Connection;
+use std::{
+    collections::HashSet,
+    io::Cursor,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::{self, available_parallelism},
+};
+
+/// Merges all blobs of every tile into one blob and vacuums the database.
+///
+/// Merged blobs no longer refer to their source files so these can't be replaced afterwards;
+/// the files are recorded per tile in `compacted_tile_file`.
+pub fn compact(database: &Path, deduplicate: bool) {
+    if !database.exists() {
+        panic!("Database file doesn't exist");
+    }
+
+    let conn = Connection::open(database).unwrap();
+
+    conn.pragma_update(None, "journal_mode", "WAL").unwrap();
+
+    let tiles: Vec<(u32, u32)> = conn
+        .prepare("SELECT x, y FROM tiles GROUP BY x, y HAVING COUNT(*) > 1")
+        .unwrap()
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect();
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS compacted_tile_file (x INTEGER, y INTEGER, file VARCHAR, PRIMARY KEY (x, y, file))",
+        (),
+    )
+    .unwrap();
+
+    println!("Compacting {} tiles", tiles.len());
+
+    let conn = Arc::new(Mutex::new(conn));
+
+    let tile_iter = Arc::new(Mutex::new(tiles.into_iter()));
+
+    thread::scope(|scope| {
+        for _ in 0..available_parallelism().unwrap().get() {
+            let conn = Arc::clone(&conn);
+
+            let tile_iter = Arc::clone(&tile_iter);
+
+            scope.spawn(move || {
+                loop {
+                    if stopped() {
+                        break;
+                    }
+
+                    let Some((x, y)) = tile_iter.lock().unwrap().next() else {
+                        break;
+                    };
+
+                    let blobs: Vec<Vec<u8>> = conn
+                        .lock()
+                        .unwrap()
+                        .prepare_cached("SELECT data FROM tiles WHERE x = ?1 AND y = ?2")
+                        .unwrap()
+                        .query_map((x, y), |row| row.get(0))
+                        .unwrap()
+                        .map(|row| row.unwrap())
+                        .collect();
+
+                    let (data, point_count, duplicate_count) = merge(blobs, deduplicate);
+
+                    let mut conn = conn.lock().unwrap();
+
+                    let tx = conn.transaction().unwrap();
+
+                    // merged blob no longer refers to its source files
+                    tx.execute(
+                        "INSERT OR IGNORE INTO compacted_tile_file (x, y, file) SELECT x, y, file FROM tiles WHERE x = ?1 AND y = ?2 AND file IS NOT NULL",
+                        (x, y),
+                    )
+                    .unwrap();
+
+                    tx.execute("DELETE FROM tiles WHERE x = ?1 AND y = ?2", (x, y))
+                        .unwrap();
+
+                    tx.execute(
+                        "INSERT INTO tiles (x, y, data, file) VALUES (?1, ?2, ?3, NULL)",
+                        (x, y, data.as_slice()),
+                    )
+                    .unwrap();
+
+                    tx.commit().unwrap();
+
+                    drop(conn);
+
+                    println!(
+                        "COMPACTED {x}/{y} {point_count} points, {duplicate_count} duplicates removed"
+                    );
+                }
+            });
+        }
+    });
+
+    if stopped() {
+        println!("Stopped; run again to compact the remaining tiles");
+
+        return;
+    }
+
+    let conn = Arc::try_unwrap(conn).unwrap().into_inner().unwrap();
+
+    println!("Vacuuming");
+
+    conn.execute("VACUUM", ()).unwrap();
+}
+
+/// Writes points of all blobs into one; blobs of the tile share the header parameters
+fn merge(blobs: Vec<Vec<u8>>, deduplicate: bool) -> (Vec<u8>, u64, u64) {
+    let mut writer = None;
+
+    let mut seen = HashSet::new();
+
+    let (mut point_count, mut duplicate_count) = (0, 0);
+
+    for data in blobs {
+        let mut reader = Reader::new(Cursor::new(data)).unwrap();
+
+        let writer = writer.get_or_insert_with(|| {
+            Writer::new(Cursor::new(Vec::new()), reader.header().clone()).unwrap()
+        });
+
+        for point in reader.points() {
+            let point = point.unwrap();
+
+            // overlapping strips may contain the same points
+            if deduplicate
+                && !seen.insert((point.x.to_bits(), point.y.to_bits(), point.z.to_bits()))
+            {
+                duplicate_count += 1;
+
+                continue;
+            }
+
+            writer.write_point(point).unwrap();
+
+            point_count += 1;
+        }
+    }
+
+    let data = writer
+        .expect("Tile has no blobs")
+        .into_inner()
+        .unwrap()
+        .into_inner();
+
+    (data, point_count, duplicate_count)
+}
@@ -1,36 +1,60 @@
-use clap::Parser;
+mod compact;
+
+use clap::{Parser, Subcommand};
+use compact::compact;
 use las::{
     Builder, Point, Reader, Transform, Vector, Writer,
     point::{Classification, Format},
 };
+use lazcommon::{
+    crs::crs,
+    input::input_files,
+    stop::{install_stop_handler, stopped},
+};
 use maptile::tile::{Tile, mercator_to_tile_coords};
 use proj::Proj;
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
 use std::path::PathBuf;
 use std::{
     collections::{HashMap, HashSet},
-    io::Cursor,
+    fs::File,
+    io::{BufWriter, Cursor, Write},
     path::Path,
     sync::{Arc, Mutex},
     thread::{self, available_parallelism},
+    time::UNIX_EPOCH,
 };
-use walkdir::WalkDir;
 
 #[derive(Parser, Debug, PartialEq)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Options {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     #[clap(long, default_value_t = true)]
     r#continue: bool,
 
-    /// Directory with *.laz files
-    directory: PathBuf, // "/home/martin/18TB"
+    /// Directory with *.las / *.laz files, glob pattern (quoted) or text file listing the files one per line
+    #[clap(required = true)]
+    input: Option<PathBuf>, // "/home/martin/18TB"
 
     /// Output database file
-    database: PathBuf, // "/home/martin/14TB/sk-new-dmr/laztiles.sqlite"
+    #[clap(required = true)]
+    database: Option<PathBuf>, // "/home/martin/14TB/sk-new-dmr/laztiles.sqlite"
 
-    /// Source projection; default is EPSG:3857
+    /// Source projection; default is read from headers of the files which must all have the same
     #[clap(long)]
     source_projection: Option<String>,
 
+    /// Vertical CRS of heights, eg. EPSG:8357 (Baltic 1957 height).
+    /// Horizontal projection must be an EPSG code. PROJ must have needed geoid grids available locally.
+    #[clap(long, requires = "target_vertical_crs")]
+    source_vertical_crs: Option<String>,
+
+    /// Vertical CRS to transform heights to, eg. EPSG:3855 (EGM2008 height)
+    #[clap(long, requires = "source_vertical_crs")]
+    target_vertical_crs: Option<String>,
+
     /// Zoom level of a tile
     #[clap(long, default_value_t = 16)]
     zoom_level: u8,
@@ -38,20 +62,54 @@ struct Options {
     /// Buffer in mercator meters
     #[clap(long, default_value_t = 30.0)]
     buffer: f64,
+
+    /// Write tiles affected by new or replaced files to this file as `z/x/y` lines
+    /// to be re-rendered by `laz2dem --tiles`. Tiles are recorded only by runs after the initial tiling
+    /// finished (not by the initial tiling nor its resumed runs) and are remembered in the database until written.
+    #[clap(long)]
+    dirty_tiles: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum Command {
+    /// Merge all blobs of every tile into one and vacuum the database. Source files of compacted tiles can't be replaced afterwards (changed ones are reported and skipped); other files still can.
+    Compact {
+        /// Database file
+        database: PathBuf,
+
+        /// Remove identical points of overlapping files
+        #[clap(long)]
+        deduplicate: bool,
+    },
 }
 
 fn main() {
     let options = Options::parse();
 
-    if options.r#continue && !options.database.exists() {
+    // workers finish their current file and stop
+    install_stop_handler("Stopping after the current files");
+
+    match options.command {
+        Some(Command::Compact {
+            ref database,
+            deduplicate,
+        }) => compact(database, deduplicate),
+        None => tile(options),
+    }
+}
+
+fn tile(options: Options) {
+    let database = options.database.as_ref().unwrap();
+
+    if options.r#continue && !database.exists() {
         panic!("Database file doesn't exist");
     }
 
-    if !options.r#continue && options.database.exists() {
+    if !options.r#continue && database.exists() {
         panic!("Database file already exists");
     }
 
-    let conn = Connection::open(options.database).unwrap();
+    let conn = Connection::open(database).unwrap();
 
     conn.pragma_update(None, "synchronous", "OFF").unwrap();
 
@@ -59,27 +117,108 @@ fn main() {
 
     if !options.r#continue {
         conn.execute(
-            "CREATE TABLE tiles (x NUMBER, y NUMBER, laz_id INTEGER PRIMARY KEY AUTOINCREMENT, data BLOB)",
+            "CREATE TABLE tiles (x NUMBER, y NUMBER, laz_id INTEGER PRIMARY KEY AUTOINCREMENT, data BLOB, file VARCHAR)",
             (),
         )
         .unwrap();
 
-        conn.execute("CREATE TABLE processed_file (name VARCHAR PRIMARY KEY)", ())
-            .unwrap();
+        conn.execute(
+            "CREATE TABLE processed_file (name VARCHAR PRIMARY KEY, size INTEGER, mtime INTEGER)",
+            (),
+        )
+        .unwrap();
+    } else {
+        migrate(&conn);
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS dirty_tile (x INTEGER, y INTEGER, PRIMARY KEY (x, y))",
+        (),
+    )
+    .unwrap();
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS metadata (name VARCHAR PRIMARY KEY, value VARCHAR)",
+        (),
+    )
+    .unwrap();
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS compacted_tile_file (x INTEGER, y INTEGER, file VARCHAR, PRIMARY KEY (x, y, file))",
+        (),
+    )
+    .unwrap();
+
+    let metadata = read_metadata(&conn);
+
+    let fixed_metadata = [
+        ("zoom_level", Some(options.zoom_level.to_string())),
+        ("buffer", Some(options.buffer.to_string())),
+        ("source_vertical_crs", options.source_vertical_crs.clone()),
+        ("vertical_crs", options.target_vertical_crs.clone()),
+    ];
+
+    // tiles of different zoom levels, buffers or projections must not be mixed in one database
+    if !metadata.is_empty() {
+        let check = |name: &str, value: Option<&String>| {
+            if metadata.get(name) != value {
+                panic!(
+                    "Database has {name} {} but {} is requested",
+                    metadata.get(name).map_or("unset", String::as_str),
+                    value.map_or("unset", String::as_str)
+                );
+            }
+        };
+
+        // unset vertical CRS means heights were not transformed so it must match too
+        for (name, value) in &fixed_metadata {
+            check(name, value.as_ref());
+        }
+
+        // source projection is read from headers unless specified
+        if options.source_projection.is_some() {
+            check("source_projection", options.source_projection.as_ref());
+        }
     }
 
+    write_metadata(
+        &conn,
+        fixed_metadata.into_iter().chain([
+            ("classes", Some("2".to_owned())),
+            ("point_format", Some("0".to_owned())),
+            ("version", Some(env!("CARGO_PKG_VERSION").to_owned())),
+        ]),
+    );
+
+    // the initial tiling (including its resumed runs) is rendered as a whole so its tiles are not dirty;
+    // databases created before metadata were stored hold finished tilings
+    let record_dirty = metadata.contains_key("initial_tiling_finished")
+        || (metadata.is_empty()
+            && conn
+                .query_row("SELECT EXISTS (SELECT 1 FROM tiles)", (), |row| row.get(0))
+                .unwrap());
+
     let conn = Arc::new(Mutex::new(conn));
 
-    let laz_iter = WalkDir::new(options.directory).into_iter();
+    let laz_iter = input_files(options.input.as_ref().unwrap()).into_iter();
 
     let laz_iter = Arc::new(Mutex::new(laz_iter));
 
     let source_projection = options.source_projection;
 
+    // CRS read from headers if `--source-projection` is not specified; all files must have the same
+    // including those processed by previous runs
+    let detected_crs = Mutex::new(metadata.get("source_projection").cloned());
+
     let zoom_level = options.zoom_level;
 
     let buffer = options.buffer;
 
+    let vertical_crs = (
+        options.source_vertical_crs.as_deref(),
+        options.target_vertical_crs.as_deref(),
+    );
+
     thread::scope(|scope| {
         for _ in 0..available_parallelism().unwrap().get() {
             let conn = Arc::clone(&conn);
@@ -88,14 +227,15 @@ fn main() {
 
             let source_projection = source_projection.clone();
 
+            let detected_crs = &detected_crs;
+
             scope.spawn(move || {
-                let proj = source_projection.map(|proj| {
-                    Proj::new_known_crs(proj.as_ref(), "EPSG:3857", None)
-                        .expect("Failed to create PROJ transformation")
-                });
+                let explicit_projection = source_projection.is_some();
+
+                let mut proj = source_projection.map(|proj| mercator_proj(&proj, vertical_crs));
 
                 loop {
-                    if Path::new("STOP").exists() {
+                    if stopped() {
                         break;
                     }
 
@@ -107,48 +247,117 @@ fn main() {
 
                     drop(lock);
 
-                    let file = file.unwrap();
-
-                    if !file
-                        .path()
-                        .extension()
-                        .map(|ext| ext == "laz")
-                        .unwrap_or(false)
-                    {
-                        continue;
-                    }
-
                     let file_name = file
-                        .path()
                         .file_name()
                         .unwrap()
                         .to_string_lossy()
                         .into_owned();
 
-                    if conn
-                        .lock()
-                        .unwrap()
-                        .prepare("SELECT COUNT(*) FROM processed_file WHERE name = ?1")
-                        .unwrap()
-                        .query([&file_name])
-                        .unwrap()
-                        .next()
+                    let metadata = file.metadata().unwrap();
+
+                    let size = metadata.len() as i64;
+
+                    let mtime = metadata
+                        .modified()
                         .unwrap()
+                        .duration_since(UNIX_EPOCH)
                         .unwrap()
-                        .get::<_, u32>(0)
+                        .as_secs() as i64;
+
+                    let processed: Option<(Option<i64>, Option<i64>)> = conn
+                        .lock()
                         .unwrap()
-                        > 0
-                    {
-                        println!("ALREADY PROCESSED {file_name}");
+                        .query_row(
+                            "SELECT size, mtime FROM processed_file WHERE name = ?1",
+                            [&file_name],
+                            |row| Ok((row.get(0)?, row.get(1)?)),
+                        )
+                        .optional()
+                        .unwrap();
+
+                    match processed {
+                        // files processed before size and mtime were recorded are considered unchanged
+                        Some((None, _) | (_, None)) => {
+                            println!("ALREADY PROCESSED {file_name}");
+
+                            continue;
+                        }
+                        Some((Some(processed_size), Some(processed_mtime)))
+                            if processed_size == size && processed_mtime == mtime =>
+                        {
+                            println!("ALREADY PROCESSED {file_name}");
+
+                            continue;
+                        }
+                        Some(_) => {
+                            let mut conn = conn.lock().unwrap();
+
+                            // merged blobs no longer refer to their source files
+                            let compacted: bool = conn
+                                .query_row(
+                                    "SELECT EXISTS (SELECT 1 FROM compacted_tile_file WHERE file = ?1)",
+                                    [&file_name],
+                                    |row| row.get(0),
+                                )
+                                .unwrap();
+
+                            if compacted {
+                                eprintln!(
+                                    "ERROR {}: file changed but its tiles were compacted; re-create the database to replace it",
+                                    file.to_string_lossy()
+                                );
+
+                                continue;
+                            }
+
+                            println!("REPLACE {file_name}");
+
+                            let tx = conn.transaction().unwrap();
+
+                            if record_dirty {
+                                tx.execute(
+                                    "INSERT OR IGNORE INTO dirty_tile (x, y) SELECT DISTINCT x, y FROM tiles WHERE file = ?1",
+                                    [&file_name],
+                                )
+                                .unwrap();
+                            }
+
+                            tx.execute("DELETE FROM tiles WHERE file = ?1", [&file_name])
+                                .unwrap();
 
-                        continue;
+                            tx.execute("DELETE FROM processed_file WHERE name = ?1", [&file_name])
+                                .unwrap();
+
+                            tx.commit().unwrap();
+                        }
+                        None => {}
                     }
 
                     println!("START {file_name}");
 
                     let mut map = HashMap::new();
 
-                    let mut reader = Reader::from_path(file.path()).unwrap();
+                    let mut reader = Reader::from_path(&file).unwrap();
+
+                    if !explicit_projection {
+                        let crs = crs(reader.header()).unwrap_or_else(|| {
+                            panic!("File {file_name} has no CRS; use --source-projection")
+                        });
+
+                        let mut detected_crs = detected_crs.lock().unwrap();
+
+                        match *detected_crs {
+                            Some(ref detected_crs) if *detected_crs != crs => panic!(
+                                "File {file_name} has CRS {crs} but previous files have {detected_crs}; use --source-projection"
+                            ),
+                            Some(_) => {}
+                            None => *detected_crs = Some(crs.clone()),
+                        }
+
+                        if proj.is_none() {
+                            proj = Some(mercator_proj(&crs, vertical_crs));
+                        }
+                    }
 
                     for point in reader.points() {
                         let point = point.unwrap();
@@ -157,9 +366,9 @@ fn main() {
                             continue;
                         }
 
-                        let (x, y) = proj.as_ref().map_or_else(
-                            || (point.x, point.y),
-                            |proj| proj.convert((point.x, point.y)).unwrap(),
+                        let (x, y, z) = proj.as_ref().map_or_else(
+                            || (point.x, point.y, point.z),
+                            |proj| proj.convert((point.x, point.y, point.z)).unwrap(),
                         );
 
                         let tile_coords: HashSet<_> = (0..4)
@@ -213,32 +422,44 @@ fn main() {
                                 .write_point(Point {
                                     x,
                                     y,
-                                    z: point.z,
+                                    z,
                                     ..Default::default()
                                 })
                                 .unwrap();
                         }
                     }
 
+                    let mut conn = conn.lock().unwrap();
+
+                    let tx = conn.transaction().unwrap();
+
                     for ((x, y), writer) in map {
                         let data = writer.into_inner().unwrap().into_inner();
 
-                        conn.lock()
-                            .unwrap()
-                            .execute(
-                                "INSERT INTO tiles (x, y, data) VALUES (?1, ?2, ?3)",
-                                (x, y, data.as_slice()),
+                        tx.execute(
+                            "INSERT INTO tiles (x, y, data, file) VALUES (?1, ?2, ?3, ?4)",
+                            (x, y, data.as_slice(), &file_name),
+                        )
+                        .unwrap();
+
+                        if record_dirty {
+                            tx.execute(
+                                "INSERT OR IGNORE INTO dirty_tile (x, y) VALUES (?1, ?2)",
+                                (x, y),
                             )
                             .unwrap();
+                        }
                     }
 
-                    conn.lock()
-                        .unwrap()
-                        .execute(
-                            "INSERT INTO processed_file (name) VALUES (?1)",
-                            [&file_name],
-                        )
-                        .unwrap();
+                    tx.execute(
+                        "INSERT INTO processed_file (name, size, mtime) VALUES (?1, ?2, ?3)",
+                        (&file_name, size, mtime),
+                    )
+                    .unwrap();
+
+                    tx.commit().unwrap();
+
+                    drop(conn);
 
                     println!("FIN {file_name}");
                 }
@@ -246,10 +467,144 @@ fn main() {
         }
     });
 
+    if stopped() {
+        println!("Stopped; run again to process the remaining files");
+    }
+
     let conn = Arc::try_unwrap(conn).unwrap().into_inner().unwrap();
 
+    write_metadata(
+        &conn,
+        [
+            (
+                "source_projection",
+                source_projection.or_else(|| detected_crs.into_inner().unwrap()),
+            ),
+            // tiles of later runs are recorded as dirty
+            (
+                "initial_tiling_finished",
+                (!stopped()).then(|| "true".to_owned()),
+            ),
+        ],
+    );
+
     if !options.r#continue {
         conn.execute("CREATE INDEX idx_tiles_xy ON tiles (x, y)", ())
             .unwrap();
+
+        conn.execute("CREATE INDEX idx_tiles_file ON tiles (file)", ())
+            .unwrap();
     }
+
+    if let Some(dirty_tiles) = options.dirty_tiles {
+        write_dirty_tiles(&conn, &dirty_tiles, zoom_level);
+    }
+}
+
+/// Creates transformation to EPSG:3857 including heights if (source, target) vertical CRSs are given
+fn mercator_proj(crs: &str, vertical_crs: (Option<&str>, Option<&str>)) -> Proj {
+    let (source, target) = match vertical_crs {
+        (Some(source_vertical_crs), Some(target_vertical_crs)) => {
+            if !crs.starts_with("EPSG:") {
+                panic!("Vertical CRS can be combined only with EPSG code of horizontal projection");
+            }
+
+            let code = |vertical_crs: &str| {
+                vertical_crs
+                    .strip_prefix("EPSG:")
+                    .unwrap_or(vertical_crs)
+                    .to_owned()
+            };
+
+            (
+                format!("{crs}+{}", code(source_vertical_crs)),
+                format!("EPSG:3857+{}", code(target_vertical_crs)),
+            )
+        }
+        _ => (crs.to_owned(), "EPSG:3857".to_owned()),
+    };
+
+    Proj::new_known_crs(&source, &target, None).expect("Failed to create PROJ transformation")
+}
+
+/// Adds columns for tracking of source files to databases created by older versions
+fn migrate(conn: &Connection) {
+    for (table, column, definition) in [
+        ("tiles", "file", "VARCHAR"),
+        ("processed_file", "size", "INTEGER"),
+        ("processed_file", "mtime", "INTEGER"),
+    ] {
+        let exists: bool = conn
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+                (table, column),
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        if !exists {
+            conn.execute(
+                &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+                (),
+            )
+            .unwrap();
+        }
+    }
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_tiles_file ON tiles (file)",
+        (),
+    )
+    .unwrap();
+}
+
+fn read_metadata(conn: &Connection) -> HashMap<String, String> {
+    conn.prepare("SELECT name, value FROM metadata")
+        .unwrap()
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect()
+}
+
+/// Stores parameters of the tiles so that `laz2dem` can use matching ones; unset values are skipped
+fn write_metadata<'a>(
+    conn: &Connection,
+    entries: impl IntoIterator<Item = (&'a str, Option<String>)>,
+) {
+    for (name, value) in entries {
+        if let Some(value) = value {
+            conn.execute(
+                "INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)",
+                (name, value),
+            )
+            .unwrap();
+        }
+    }
+}
+
+fn write_dirty_tiles(conn: &Connection, path: &Path, zoom_level: u8) {
+    let mut writer = BufWriter::new(File::create(path).unwrap());
+
+    let mut stmt = conn.prepare("SELECT x, y FROM dirty_tile").unwrap();
+
+    let mut rows = stmt.query(()).unwrap();
+
+    let mut count = 0;
+
+    while let Some(row) = rows.next().unwrap() {
+        let x: u32 = row.get(0).unwrap();
+
+        let y: u32 = row.get(1).unwrap();
+
+        writeln!(writer, "{zoom_level}/{x}/{y}").unwrap();
+
+        count += 1;
+    }
+
+    writer.flush().unwrap();
+
+    conn.execute("DELETE FROM dirty_tile", ()).unwrap();
+
+    println!("Written {count} dirty tiles");
 }